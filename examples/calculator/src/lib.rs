@@ -0,0 +1,15 @@
+//! Payloads of the `add_one` calculator service, shared by the `hello` guest and the host's native
+//! implementation.
+#![no_std]
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Arg {
+    pub foo: i32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Response {
+    pub bar: i32,
+}
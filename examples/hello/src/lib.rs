@@ -1,38 +1,41 @@
 #[macro_use]
 extern crate log;
 
+use calculator::{Arg, Response};
 use cstr::cstr;
-use we_rt::{init_logger, invoke, callback};
-use serde::{Deserialize, Serialize};
 use std::ffi::CStr;
-use std::sync::Once;
 
 #[no_mangle]
 pub static NAME: &CStr = cstr!(b"hello");
 
-static LOG_INIT: Once = Once::new();
-
-#[derive(Serialize, Clone, Debug)]
-struct Arg {
-    foo: i32,
+#[we_rt::service]
+pub trait Calculator {
+    async fn add_one(&self, arg: Arg) -> Response;
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-struct Response {
-    bar: i32,
+struct Calc;
+
+#[we_rt::service]
+impl Calculator for Calc {
+    async fn add_one(&self, arg: Arg) -> Response {
+        Response { bar: arg.foo + 1 }
+    }
 }
 
 #[no_mangle]
-extern "C" fn hello(cb: i64, user_data: i64) {
-    LOG_INIT.call_once(init_logger);
-    let rt = we_rt::Runtime::new();
-
-    rt.spawn(async move {
-        info!("log inside wasm");
-        let response = Response { bar: 1 };
-        callback(&bincode::serialize(&response).unwrap(), cb, user_data);
-
-        let result: Result<Response, _> = invoke("hello", "add_one", Arg { foo: 1 }).await;
-        info!("{:?}", result.unwrap());
-    })
+extern "C" fn init() {
+    we_rt::init_logger();
+    we_rt::serve(CalculatorServer::with_name("hello", Calc));
+}
+
+#[we_rt::export]
+async fn hello() -> Response {
+    info!("log inside wasm");
+
+    let result = CalculatorClient::with_name("host").add_one(Arg { foo: 1 }).await;
+    info!("{:?}", result.unwrap());
+    let result = CalculatorClient::with_name("hello").add_one(Arg { foo: 2 }).await;
+    info!("{:?}", result.unwrap());
+
+    Response { bar: 1 }
 }
@@ -1,9 +1,76 @@
+use alloc::boxed::Box;
 use alloc::vec::Vec;
-use core::ffi::c_void;
+use core::sync::atomic::{AtomicU64, Ordering};
+use std::collections::HashMap;
+use std::sync::Mutex;
 
-/// trampoline function for preserve closure type information
-/// `ptr`, `size` and `cap` comes from a Vec<u8>
-pub unsafe fn trampoline<F>(user_data: *mut c_void, ptr: *mut u8, size: usize, cap: usize) where F: FnMut(Vec<u8>) {
-    let data = unsafe { Vec::from_raw_parts(ptr, size, cap) };
-    (*(user_data as *mut F))(data)
+type Continuation = Box<dyn FnOnce(Vec<u8>) + Send>;
+
+/// Continuations of calls into guests, keyed by an opaque request id.
+///
+/// Guests only ever see request ids, a reply resolves the call it names if
+/// it comes from the same `owner` the call was registered for.
+#[derive(Default)]
+pub struct PendingCalls {
+    next_id: AtomicU64,
+    calls: Mutex<HashMap<u64, (u64, Continuation)>>,
+}
+
+impl PendingCalls {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a continuation for a call into `owner`, returns the request id.
+    pub fn register<F>(&self, owner: u64, f: F) -> u64 where F: FnOnce(Vec<u8>) + Send + 'static {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.calls.lock().unwrap().insert(id, (owner, Box::new(f)));
+        id
+    }
+
+    /// Resolve request `id` with `data`, returns `false` if `owner` has no such pending call.
+    pub fn resolve(&self, owner: u64, id: u64, data: Vec<u8>) -> bool {
+        let continuation = {
+            let mut calls = self.calls.lock().unwrap();
+            match calls.get(&id) {
+                Some((call_owner, _)) if *call_owner == owner => calls.remove(&id).map(|(_, f)| f),
+                _ => None,
+            }
+        };
+        match continuation {
+            Some(f) => {
+                f(data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Number of calls pending on `owner`.
+    pub fn pending(&self, owner: u64) -> usize {
+        self.calls.lock().unwrap().values().filter(|(call_owner, _)| *call_owner == owner).count()
+    }
+
+    /// Resolve every call pending on `owner` with `data`, returns how many there were.
+    pub fn resolve_owner(&self, owner: u64, data: &[u8]) -> usize {
+        let continuations = {
+            let mut calls = self.calls.lock().unwrap();
+            let ids = calls
+                .iter()
+                .filter(|(_, (call_owner, _))| *call_owner == owner)
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>();
+            ids.iter().filter_map(|id| calls.remove(id)).map(|(_, f)| f).collect::<Vec<_>>()
+        };
+        let resolved = continuations.len();
+        for f in continuations {
+            f(data.to_vec());
+        }
+        resolved
+    }
+
+    /// Drop the continuation of request `id` without calling it.
+    pub fn cancel(&self, id: u64) -> bool {
+        self.calls.lock().unwrap().remove(&id).is_some()
+    }
 }
\ No newline at end of file
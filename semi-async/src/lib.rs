@@ -1,32 +1,29 @@
 extern crate alloc;
 
+#[cfg(target_arch="wasm32")]
 use alloc::rc::Rc;
+#[cfg(target_arch="wasm32")]
 use core::cell::RefCell;
 use core::future::Future;
 use core::pin::Pin;
 use core::task::{Context, Poll, Waker};
 #[cfg(not(target_arch="wasm32"))]
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-#[cfg(target_arch="wasm32")]
 pub mod rt;
-#[cfg(target_arch="wasm32")]
+/// Calls the guest made into the host, single threaded, guests keep their table in a thread local.
 pub mod wasm_callback;
-#[cfg(not(target_arch="wasm32"))]
+/// Calls the host made into guests, shared by the threads of the host.
 pub mod host_callback;
 
-#[cfg(target_arch="wasm32")]
 pub use rt::runtime::Runtime;
-#[cfg(target_arch="wasm32")]
-pub use wasm_callback::trampoline;
-#[cfg(not(target_arch="wasm32"))]
-pub use host_callback::trampoline;
+pub use rt::task::Task;
 
 
 #[cfg(target_arch="wasm32")]
 pub type AsyncResultInner<T> = Rc<RefCell<Inner<T>>>;
 #[cfg(not(target_arch="wasm32"))]
-pub type AsyncResultInner<T> = Arc<RefCell<Inner<T>>>;
+pub type AsyncResultInner<T> = Arc<Mutex<Inner<T>>>;
 
 #[derive(Debug, Clone)]
 pub struct AsyncResult<T> {
@@ -39,8 +36,9 @@ pub struct Inner<T> {
     v: Option<MaybeTaken<T>>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub enum MaybeTaken<T> {
+    #[default]
     Taken,
     StillThere(T),
 }
@@ -53,7 +51,7 @@ impl<T> Default for AsyncResult<T> {
         };
         #[cfg(not(target_arch="wasm32"))]
         return Self {
-            inner: Arc::new(RefCell::new(Inner::default()))
+            inner: Arc::new(Mutex::new(Inner::default()))
         };
     }
 }
@@ -67,17 +65,11 @@ impl<T> Default for Inner<T> {
     }
 }
 
-impl<T> Default for MaybeTaken<T> {
-    fn default() -> Self {
-        Self::Taken
-    }
-}
-
 impl <T> Future for AsyncResult<T> {
     type Output = T;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let mut inner = self.inner.borrow_mut();
+        let mut inner = lock(&self.inner);
 
         if inner.v.is_some() {
             match inner.v.replace(MaybeTaken::Taken).unwrap() {
@@ -92,11 +84,40 @@ impl <T> Future for AsyncResult<T> {
 }
 
 impl<T> AsyncResult<T> {
+    /// An `AsyncResult` which is already resolved with `value`.
+    pub fn ready(value: T) -> Self {
+        let result = Self::default();
+        lock(&result.inner).set_value(value);
+        result
+    }
+
     pub fn clone_inner(&self) -> AsyncResultInner<T> {
         self.inner.clone()
     }
 }
 
+/// Set the value of the `AsyncResult` owning `inner` and wake the task awaiting it.
+pub fn resolve<T>(inner: &AsyncResultInner<T>, value: T) {
+    let waker = {
+        let mut inner = lock(inner);
+        inner.set_value(value);
+        inner.task.take()
+    };
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+#[cfg(target_arch="wasm32")]
+fn lock<T>(inner: &AsyncResultInner<T>) -> core::cell::RefMut<'_, Inner<T>> {
+    inner.borrow_mut()
+}
+
+#[cfg(not(target_arch="wasm32"))]
+fn lock<T>(inner: &AsyncResultInner<T>) -> std::sync::MutexGuard<'_, Inner<T>> {
+    inner.lock().unwrap()
+}
+
 impl<T> Inner<T> {
     pub fn set_value(&mut self, value: T) {
         self.v = Some(MaybeTaken::StillThere(value));
@@ -16,6 +16,12 @@ struct RuntimeInner {
     is_spinning: Cell<bool>,
 }
 
+impl Default for Runtime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Runtime {
     pub fn new() -> Self {
         Self {
@@ -26,11 +32,11 @@ impl Runtime {
         }
     }
 
-    pub fn spawn<F>(&self, future: F)
+    pub fn spawn<F>(&self, future: F) -> Task
         where
             F: Future<Output = ()> + 'static,
     {
-        super::task::Task::spawn(Box::pin(future), self.clone());
+        Task::spawn(Box::pin(future), self.clone())
     }
 
     pub fn push_task(&self, task: Task) {
@@ -3,7 +3,7 @@ use alloc::rc::Rc;
 use core::cell::{RefCell, Cell};
 use core::future::Future;
 use core::pin::Pin;
-use core::task::{RawWaker, Waker, Context, Poll};
+use core::task::{RawWaker, Waker, Context};
 
 use super::runtime::Runtime;
 
@@ -15,6 +15,7 @@ pub struct Task {
 struct TaskInner {
     inner: RefCell<Option<Inner>>,
     is_queued: Cell<bool>,
+    is_cancelled: Cell<bool>,
     runtime: Runtime,
 }
 
@@ -28,19 +29,34 @@ impl Task {
         Task::from(TaskInner {
             inner: RefCell::new(None),
             is_queued: Cell::new(false),
+            is_cancelled: Cell::new(false),
             runtime,
         })
     }
-    pub fn spawn(future: Pin<Box<dyn Future<Output = ()> + 'static>>, runtime: Runtime) {
+    pub fn spawn(future: Pin<Box<dyn Future<Output = ()> + 'static>>, runtime: Runtime) -> Self {
         let task = Self::new(runtime);
         let waker: Waker = task.clone().into();
         task.inner.inner.replace(Some(Inner { future, waker }));
-        task.inner.wake_by_ref()
+        task.inner.wake_by_ref();
+        task
     }
 
     pub fn run(&self) {
         self.inner.run()
     }
+
+    /// Drop the future of the task, it is not polled anymore.
+    pub fn cancel(&self) {
+        self.inner.is_cancelled.set(true);
+        if let Ok(mut inner) = self.inner.inner.try_borrow_mut() {
+            *inner = None;
+        }
+    }
+
+    /// Whether the future completed or the task was cancelled.
+    pub fn is_done(&self) -> bool {
+        self.inner.is_cancelled.get() || self.inner.inner.try_borrow().is_ok_and(|inner| inner.is_none())
+    }
 }
 
 impl TaskInner {
@@ -66,7 +82,8 @@ impl TaskInner {
             inner.future.as_mut().poll(&mut cx)
         };
 
-        if let Poll::Ready(()) = poll {
+        // a task cancelling itself while running is dropped once it yields
+        if poll.is_ready() || self.is_cancelled.get() {
             *borrow = None;
         }
     }
@@ -84,14 +101,14 @@ impl From<Rc<TaskInner>> for Task {
     }
 }
 
-impl Into<Waker> for Task {
-    fn into(self) -> Waker {
-        unsafe { Waker::from_raw(self.into()) }
+impl From<Task> for Waker {
+    fn from(task: Task) -> Self {
+        unsafe { Waker::from_raw(task.into()) }
     }
 }
 
-impl Into<RawWaker> for Task {
-    fn into(self) -> RawWaker {
+impl From<Task> for RawWaker {
+    fn from(task: Task) -> Self {
         use core::mem::ManuallyDrop;
         use core::task::RawWakerVTable;
 
@@ -117,6 +134,6 @@ impl Into<RawWaker> for Task {
         const VTABLE: RawWakerVTable =
             RawWakerVTable::new(raw_clone, raw_wake, raw_wake_by_ref, raw_drop);
 
-        RawWaker::new(Rc::into_raw(self.inner) as *const (), &VTABLE)
+        RawWaker::new(Rc::into_raw(task.inner) as *const (), &VTABLE)
     }
 }
\ No newline at end of file
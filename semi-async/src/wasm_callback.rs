@@ -1,9 +1,43 @@
-use core::ffi::c_void;
-
-/// trampoline function for preserve closure type information
-pub unsafe extern "C" fn trampoline<F>(user_data: *mut c_void, ptr: *const u8, size: usize)
-    where
-        F: FnMut(&[u8]),
-{
-    (*(user_data as *mut F))(alloc::slice::from_raw_parts(ptr, size))
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::cell::{Cell, RefCell};
+
+type Continuation = Box<dyn FnOnce(&[u8])>;
+
+/// Continuations of calls into the host, keyed by the request id handed to the host.
+#[derive(Default)]
+pub struct PendingCalls {
+    next_id: Cell<u64>,
+    calls: RefCell<BTreeMap<u64, Continuation>>,
+}
+
+impl PendingCalls {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a continuation, returns the request id.
+    pub fn register<F>(&self, f: F) -> u64 where F: FnOnce(&[u8]) + 'static {
+        let id = self.next_id.get() + 1;
+        self.next_id.set(id);
+        self.calls.borrow_mut().insert(id, Box::new(f));
+        id
+    }
+
+    /// Resolve request `id` with `data`, returns `false` if there is no such pending call.
+    pub fn resolve(&self, id: u64, data: &[u8]) -> bool {
+        let continuation = self.calls.borrow_mut().remove(&id);
+        match continuation {
+            Some(f) => {
+                f(data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drop the continuation of request `id` without calling it.
+    pub fn cancel(&self, id: u64) -> bool {
+        self.calls.borrow_mut().remove(&id).is_some()
+    }
 }
\ No newline at end of file
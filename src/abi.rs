@@ -0,0 +1,194 @@
+use wasmer::{ExternType, FunctionType, Module, Type};
+use we_proto::abi::{parse_descriptor, Signature, ValType, CANCEL, DISPATCH, EXPORTS, IMPORTS, OPTIONAL_EXPORTS, SET_FORMAT};
+
+use crate::error::Error;
+
+pub use we_proto::abi::{
+    ABI_SECTION, ABI_VERSION, FEATURE_CANCEL, FEATURE_DISPATCH, FEATURE_FORMATS, FEATURE_WASI, IMPORT_MODULE,
+    WASI_MODULES,
+};
+
+/// `(version, features)` a guest declares in its `we_abi` section.
+pub fn descriptor(module: &Module) -> Option<(u32, u32)> {
+    parse_descriptor(&module.custom_sections(ABI_SECTION).next()?)
+}
+
+/// Whether `ty` has the parameters `params` and no results.
+pub fn is_procedure(ty: &FunctionType, params: &[ValType]) -> bool {
+    *ty == FunctionType::new(wasm_types(params), vec![])
+}
+
+/// Validate the ABI descriptor, imports and exports of a module before it is instantiated.
+///
+/// WASI imports are only allowed if `wasi` is enabled for the module, wasmer-wasi checks their signatures.
+/// Every problem found is listed in the returned `Error::Abi`.
+pub fn check(module: &Module, wasi: bool) -> Result<(), Error> {
+    let (version, features) = match descriptor(module) {
+        Some(descriptor) => descriptor,
+        None => return Err(Error::Abi(format!("missing or invalid `{}` section, not built with we-rt?", ABI_SECTION))),
+    };
+    if version != ABI_VERSION {
+        return Err(Error::Abi(format!("guest ABI version {}, host supports {}", version, ABI_VERSION)));
+    }
+
+    let mut problems = Vec::new();
+    if features & FEATURE_WASI != 0 && !wasi {
+        problems.push("module is built for WASI, enable `wasi` for it".to_string());
+    }
+    for import in module.imports() {
+        let (module, name) = (import.module(), import.name());
+        if WASI_MODULES.contains(&module) {
+            if !wasi && features & FEATURE_WASI == 0 {
+                problems.push(format!("import {}::{} needs WASI", module, name));
+            }
+            continue;
+        }
+        let expected = if module == IMPORT_MODULE {
+            IMPORTS.iter().find(|signature| signature.name == name)
+        } else {
+            None
+        };
+        match (expected, import.ty()) {
+            (Some(signature), ExternType::Function(ty)) => check_signature("import", signature, ty, &mut problems),
+            (Some(_), ty) => problems.push(format!("import {}::{} is a {:?}, expected a function", module, name, ty)),
+            (None, _) => problems.push(format!("unknown import {}::{}", module, name)),
+        }
+    }
+
+    let mut required = EXPORTS.to_vec();
+    if features & FEATURE_DISPATCH != 0 {
+        required.push(DISPATCH);
+    }
+    if features & FEATURE_CANCEL != 0 {
+        required.push(CANCEL);
+    }
+    if features & FEATURE_FORMATS != 0 {
+        required.push(SET_FORMAT);
+    }
+    for signature in required.iter() {
+        match export_type(module, signature.name) {
+            Some(ExternType::Function(ty)) => check_signature("export", signature, &ty, &mut problems),
+            Some(ty) => problems.push(format!("export {} is a {:?}, expected a function", signature.name, ty)),
+            None => problems.push(format!("missing export {}", signature.name)),
+        }
+    }
+    for signature in OPTIONAL_EXPORTS.iter() {
+        if let Some(ExternType::Function(ty)) = export_type(module, signature.name) {
+            check_signature("export", signature, &ty, &mut problems)
+        }
+    }
+    match export_type(module, "memory") {
+        Some(ExternType::Memory(_)) => {}
+        _ => problems.push("missing export memory".to_string()),
+    }
+    match export_type(module, "NAME") {
+        Some(ExternType::Global(ty)) if ty.ty == Type::I32 => {}
+        _ => problems.push("missing export NAME".to_string()),
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Abi(problems.join(", ")))
+    }
+}
+
+fn export_type(module: &Module, name: &str) -> Option<ExternType> {
+    module.exports().find(|export| export.name() == name).map(|export| export.ty().clone())
+}
+
+fn check_signature(kind: &str, signature: &Signature, ty: &FunctionType, problems: &mut Vec<String>) {
+    let expected = FunctionType::new(wasm_types(signature.params), wasm_types(signature.results));
+    if *ty != expected {
+        problems.push(format!("{} {} has type {}, expected {}", kind, signature.name, ty, expected));
+    }
+}
+
+fn wasm_types(types: &[ValType]) -> Vec<Type> {
+    types
+        .iter()
+        .map(|ty| match ty {
+            ValType::I32 => Type::I32,
+            ValType::I64 => Type::I64,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmer::Store;
+
+    use super::*;
+    use crate::testing::{guest, module, with_section};
+
+    fn check_guest(wasm: Vec<u8>, wasi: bool) -> Result<(), String> {
+        let module = Module::new(&Store::default(), wasm).unwrap();
+        check(&module, wasi).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn accepts_guests_matching_the_abi() {
+        assert_eq!(check_guest(guest(0, ""), false), Ok(()));
+        let features = FEATURE_DISPATCH | FEATURE_CANCEL | FEATURE_FORMATS;
+        let exports = r#"
+            (import "__wasm_everything_runtime__" "invoke" (func (param i32 i32 i64)))
+            (func (export "_wasm_dispatch") (param i32 i32 i64) (result i32) (i32.const 1))
+            (func (export "_wasm_cancel") (param i64) (result i32) (i32.const 1))
+            (func (export "_wasm_set_format") (param i32) (result i32) (i32.const 1))
+            (func (export "init"))
+        "#;
+        assert_eq!(check_guest(guest(features, exports), false), Ok(()));
+    }
+
+    #[test]
+    fn refuses_guests_without_a_descriptor() {
+        let error = check_guest(module(r#"(memory (export "memory") 1)"#), false).unwrap_err();
+        assert!(error.contains("missing or invalid `we_abi` section"), "{}", error);
+
+        let wasm = with_section(module(""), ABI_SECTION, &[3, 0, 0]);
+        assert!(check_guest(wasm, false).is_err());
+    }
+
+    #[test]
+    fn refuses_other_abi_versions() {
+        let mut descriptor = we_proto::abi::descriptor(0);
+        descriptor[0] = ABI_VERSION as u8 + 1;
+        let wasm = with_section(module(""), ABI_SECTION, &descriptor);
+        let error = check_guest(wasm, false).unwrap_err();
+        assert_eq!(error, format!("abi error: guest ABI version {}, host supports {}", ABI_VERSION + 1, ABI_VERSION));
+    }
+
+    #[test]
+    fn lists_every_problem() {
+        let fields = r#"
+            (import "__wasm_everything_runtime__" "invoke" (func (param i32 i32)))
+            (import "__wasm_everything_runtime__" "fork" (func))
+            (import "env" "abort" (func))
+            (func (export "_wasm_dispatch") (param i32 i32 i64))
+            (func (export "init") (param i32))
+        "#;
+        let error = check_guest(guest(FEATURE_DISPATCH | FEATURE_CANCEL, fields), false).unwrap_err();
+        let problems = [
+            "import invoke has type [I32, I32] -> [], expected [I32, I32, I64] -> []",
+            "unknown import __wasm_everything_runtime__::fork",
+            "unknown import env::abort",
+            "export _wasm_dispatch has type [I32, I32, I64] -> [], expected [I32, I32, I64] -> [I32]",
+            "missing export _wasm_cancel",
+            "export init has type [I32] -> [], expected [] -> []",
+        ];
+        for problem in problems.iter() {
+            assert!(error.contains(problem), "{} not in {}", problem, error);
+        }
+    }
+
+    #[test]
+    fn wasi_imports_need_wasi() {
+        let fields = r#"(import "wasi_snapshot_preview1" "proc_exit" (func (param i32)))"#;
+        let error = check_guest(guest(0, fields), false).unwrap_err();
+        assert!(error.contains("import wasi_snapshot_preview1::proc_exit needs WASI"), "{}", error);
+        assert_eq!(check_guest(guest(0, fields), true), Ok(()));
+
+        let error = check_guest(guest(FEATURE_WASI, ""), false).unwrap_err();
+        assert!(error.contains("module is built for WASI"), "{}", error);
+    }
+}
@@ -0,0 +1,124 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use wasmer::{CompilerConfig, Store};
+
+use crate::metering;
+
+/// Compiler turning wasm into machine code, trading compile time for execution speed.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Compiler {
+    /// Fast compilation, fast code
+    Cranelift,
+    /// Fastest compilation, slowest code, requires the `singlepass` feature
+    Singlepass,
+    /// Slow compilation, fastest code, requires the `llvm` feature
+    Llvm,
+}
+
+/// Engine loading compiled code.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Engine {
+    /// Code is loaded into executable memory
+    Jit,
+    /// Code is linked into a shared library, requires the `native` feature and a system linker
+    Native,
+}
+
+/// Compiler and engine a module is compiled with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Backend {
+    pub compiler: Compiler,
+    pub engine: Engine,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Self { compiler: Compiler::Cranelift, engine: Engine::Jit }
+    }
+}
+
+impl Backend {
+    /// This backend with the compiler or engine a module asks for.
+    pub fn with(self, compiler: Option<Compiler>, engine: Option<Engine>) -> Self {
+        Self { compiler: compiler.unwrap_or(self.compiler), engine: engine.unwrap_or(self.engine) }
+    }
+
+    /// A store compiling with this backend, every module is metered.
+    ///
+    /// The store can compile a single module, see `metering::middleware`.
+    pub fn store(&self) -> anyhow::Result<Store> {
+        let mut config: Box<dyn CompilerConfig> = match self.compiler {
+            Compiler::Cranelift => Box::new(wasmer::Cranelift::default()),
+            #[cfg(feature = "singlepass")]
+            Compiler::Singlepass => Box::new(wasmer::Singlepass::default()),
+            #[cfg(feature = "llvm")]
+            Compiler::Llvm => Box::new(wasmer::LLVM::default()),
+            #[allow(unreachable_patterns)]
+            compiler => anyhow::bail!("{} compiler is not available, rebuild with `--features {}`", compiler, compiler),
+        };
+        config.push_middleware(metering::middleware());
+
+        let store = match self.engine {
+            Engine::Jit => Store::new(&wasmer::JIT::new(config).engine()),
+            #[cfg(feature = "native")]
+            Engine::Native => Store::new(&wasmer::Native::new(config).engine()),
+            #[allow(unreachable_patterns)]
+            engine => anyhow::bail!("{} engine is not available, rebuild with `--features {}`", engine, engine),
+        };
+        Ok(store)
+    }
+}
+
+impl fmt::Display for Compiler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compiler::Cranelift => "cranelift",
+            Compiler::Singlepass => "singlepass",
+            Compiler::Llvm => "llvm",
+        })
+    }
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Engine::Jit => "jit",
+            Engine::Native => "native",
+        })
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.compiler, self.engine)
+    }
+}
+
+impl FromStr for Compiler {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cranelift" => Ok(Compiler::Cranelift),
+            "singlepass" => Ok(Compiler::Singlepass),
+            "llvm" => Ok(Compiler::Llvm),
+            _ => Err(format!("unknown compiler {}, expected cranelift, singlepass or llvm", s)),
+        }
+    }
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jit" => Ok(Engine::Jit),
+            "native" | "dylib" => Ok(Engine::Native),
+            _ => Err(format!("unknown engine {}, expected jit or native", s)),
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use wasmer::{Module, Store};
+
+use crate::backend::Backend;
+use crate::manifest::Limits;
+
+/// Version of the code the host compiles into every module, the metering middleware and its costs
+/// and the memory styles of `limits::LimitingTunables`. Bump it on any change to them, the cache
+/// would otherwise load artifacts compiled the old way.
+const CODEGEN_VERSION: u32 = 1;
+
+/// Compiled modules on disk, keyed by the hash of the wasm and of everything the compiled code depends on.
+///
+/// Artifacts are loaded without validation, the directory must only be writable by the host.
+pub struct ModuleCache {
+    dir: PathBuf,
+}
+
+impl ModuleCache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Load the module at `path` from the cache, compiling it with `store` and caching it on a miss.
+    ///
+    /// `backend` is the backend `store` was built with.
+    pub fn load(&self, store: &Store, backend: Backend, path: &Path, limits: &Limits) -> anyhow::Result<Module> {
+        let wasm = fs::read(path)?;
+        let artifact = self.dir.join(format!("{}.module", self.key(store, backend, &wasm, limits)));
+
+        let cached = fs::read(&artifact).ok().and_then(|bytes| {
+            // safety: artifacts are only written by `save`, for the same engine and wasm
+            match unsafe { Module::deserialize(store, &bytes) } {
+                Ok(module) => Some(module),
+                Err(e) => {
+                    warn!("ignoring broken cache entry {}: {}", artifact.display(), e);
+                    None
+                }
+            }
+        });
+        let mut module = match cached {
+            Some(module) => {
+                debug!("loaded {} from {}", path.display(), artifact.display());
+                module
+            }
+            None => {
+                let module = Module::new(store, &wasm)?;
+                if let Err(e) = self.save(&module, &artifact) {
+                    warn!("cannot cache {}: {}", path.display(), e);
+                }
+                module
+            }
+        };
+        if let Some(name) = path.canonicalize()?.to_str() {
+            module.set_name(name);
+        }
+        Ok(module)
+    }
+
+    /// Write the artifact through a temporary file, concurrent hosts never read a partial artifact.
+    fn save(&self, module: &Module, artifact: &Path) -> anyhow::Result<()> {
+        let tmp = artifact.with_extension(format!("{}.tmp", std::process::id()));
+        fs::write(&tmp, module.serialize()?)?;
+        fs::rename(&tmp, artifact)?;
+        Ok(())
+    }
+
+    /// A new wasmer, host or codegen version, backend, target or memory limit misses the cache instead of loading stale code.
+    fn key(&self, store: &Store, backend: Backend, wasm: &[u8], limits: &Limits) -> String {
+        let target = store.engine().target();
+        let parts = [
+            wasmer::VERSION.to_string(),
+            env!("CARGO_PKG_VERSION").to_string(),
+            CODEGEN_VERSION.to_string(),
+            backend.to_string(),
+            target.triple().to_string(),
+            format!("{:?}", target.cpu_features()),
+            // memory limits change the memory style compiled into the code
+            format!("{:?}", limits.memory_pages),
+        ];
+        let mut hasher = blake3::Hasher::new();
+        for part in parts.iter() {
+            hasher.update(part.as_bytes());
+            hasher.update(&[0]);
+        }
+        hasher.update(wasm);
+        hasher.finalize().to_hex().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{Compiler, Engine};
+
+    fn cache() -> (tempfile::TempDir, ModuleCache) {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ModuleCache::new(dir.path()).unwrap();
+        (dir, cache)
+    }
+
+    fn key(cache: &ModuleCache, backend: Backend, wasm: &[u8], memory_pages: Option<u32>) -> String {
+        let store = Store::default();
+        cache.key(&store, backend, wasm, &Limits { memory_pages, fuel: None })
+    }
+
+    #[test]
+    fn keys_are_stable() {
+        let (_first_dir, first) = cache();
+        let (_second_dir, second) = cache();
+        let backend = Backend::default();
+        assert_eq!(key(&first, backend, b"wasm", None), key(&first, backend, b"wasm", None));
+        assert_eq!(key(&first, backend, b"wasm", None), key(&second, backend, b"wasm", None));
+        assert_eq!(key(&first, backend, b"wasm", None).len(), 64);
+    }
+
+    #[test]
+    fn keys_change_with_what_the_code_depends_on() {
+        let (_dir, cache) = cache();
+        let backend = Backend::default();
+        let key = |backend, wasm: &[u8], memory_pages| key(&cache, backend, wasm, memory_pages);
+        let base = key(backend, b"wasm", None);
+        assert_ne!(base, key(backend, b"wasm2", None));
+        assert_ne!(base, key(backend, b"wasm", Some(16)));
+        assert_ne!(base, key(backend.with(Some(Compiler::Singlepass), None), b"wasm", None));
+        assert_ne!(base, key(backend.with(None, Some(Engine::Native)), b"wasm", None));
+    }
+
+    #[test]
+    fn fuel_limits_share_artifacts() {
+        let (_dir, cache) = cache();
+        let store = Store::default();
+        let backend = Backend::default();
+        let limited = Limits { memory_pages: None, fuel: Some(1000) };
+        assert_eq!(cache.key(&store, backend, b"wasm", &limited), cache.key(&store, backend, b"wasm", &Limits::default()));
+    }
+}
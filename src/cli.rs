@@ -0,0 +1,70 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+use we_codec::Format;
+
+use crate::backend::{Compiler, Engine};
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "wasm-everything")]
+pub enum Command {
+    /// Load wasm modules and call an exported entry function
+    Run {
+        /// Wasm modules to load, each is registered under the `NAME` it exports
+        #[structopt(required = true, parse(from_os_str))]
+        modules: Vec<PathBuf>,
+        /// Export to call as the entry function
+        #[structopt(long, short)]
+        entry: String,
+        /// `NAME` of the module exporting the entry function, defaults to the first module
+        #[structopt(long, short)]
+        module: Option<String>,
+        /// Format the entry function replies in: bincode, json, msgpack or cbor
+        #[structopt(long, default_value = "bincode")]
+        format: Format,
+        /// Provide WASI to the modules, without access to any directory but those given with `--dir`
+        #[structopt(long)]
+        wasi: bool,
+        /// Host directory WASI modules can access, implies `--wasi`
+        #[structopt(long = "dir", parse(from_os_str))]
+        dirs: Vec<PathBuf>,
+        /// Host the modules may make HTTP requests to, see `allow` in the manifest
+        #[structopt(long = "allow-http")]
+        allow_http: Vec<String>,
+        #[structopt(flatten)]
+        options: HostOptions,
+    },
+    /// Load the modules described by a deployment manifest
+    Deploy {
+        /// `.toml` or `.yaml` manifest file
+        #[structopt(parse(from_os_str))]
+        manifest: PathBuf,
+        /// Reload modules when their file changes, `SIGHUP` reloads all of them
+        #[structopt(long)]
+        watch: bool,
+        #[structopt(flatten)]
+        options: HostOptions,
+    },
+}
+
+/// Options shared by every command, they override the manifest.
+#[derive(StructOpt, Debug)]
+pub struct HostOptions {
+    /// Directory to cache compiled modules in
+    #[structopt(long, parse(from_os_str))]
+    pub cache_dir: Option<PathBuf>,
+    /// Compiler for modules not choosing one: cranelift, singlepass or llvm
+    #[structopt(long)]
+    pub compiler: Option<Compiler>,
+    /// Engine for modules not choosing one: jit or native
+    #[structopt(long)]
+    pub engine: Option<Engine>,
+    /// Directory to persist the key-value store of guests in, it is kept in memory otherwise
+    #[structopt(long, parse(from_os_str))]
+    pub kv_dir: Option<PathBuf>,
+    /// Address to serve guest exports over HTTP on, as `POST /{module}/{function}`
+    #[structopt(long)]
+    pub listen: Option<SocketAddr>,
+}
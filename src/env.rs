@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+
+use log::LevelFilter;
+use once_cell::sync::OnceCell;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::UnboundedSender;
+use wasmer::{ExportError, Global, Instance, LazyInit, Memory, NativeFunc, WasmerEnv};
+use we_codec::Format;
+
+use crate::error::{encode_reply, Error};
+use crate::http::HttpClient;
+use crate::kv::{KvAccess, Namespace};
+use crate::lane::Lane;
+use crate::manifest::ModuleSpec;
+use crate::memory::{GuestMemory, GuestPtr, GuestSlice};
+use crate::metering;
+use crate::GLOBAL_INSTANCE_MAP;
+
+/// Log records of guests, with the name and log level of their module and the format of the record.
+pub type LogChannel = UnboundedSender<(String, LevelFilter, Format, Vec<u8>)>;
+
+#[derive(WasmerEnv, Clone)]
+pub struct Env {
+    name: OnceCell<Option<String>>,
+    instance_id: u64,
+    lane: Arc<Lane>,
+    #[wasmer(export(name = "NAME"))]
+    name_ptr: LazyInit<Global>,
+    #[wasmer(export)]
+    memory: LazyInit<Memory>,
+    #[wasmer(export(name = "_wasm_malloc"))]
+    malloc: LazyInit<NativeFunc<i32, i32>>,
+    #[wasmer(export(name = "_wasm_free"))]
+    free: LazyInit<NativeFunc<(i32, i32)>>,
+    #[wasmer(export(name = "_wasm_resolve"))]
+    resolve: LazyInit<NativeFunc<(i64, i32, i32), i32>>,
+    #[wasmer(export(name = "wasmer_metering_remaining_points", optional = true))]
+    remaining_points: LazyInit<Global>,
+    #[wasmer(export(name = "wasmer_metering_points_exhausted", optional = true))]
+    points_exhausted: LazyInit<Global>,
+    pub channel: LogChannel,
+    pub log_level: LevelFilter,
+    pub config: Arc<HashMap<String, String>>,
+    /// Calls into services the guest made which are still waiting for a reply
+    pub outstanding: Arc<AtomicUsize>,
+    /// Runtime host imports schedule work on, such as timers
+    pub runtime: Handle,
+    pub kv: Option<KvAccess>,
+    /// Outbound HTTP, guests without it cannot make requests
+    pub http: Option<HttpClient>,
+}
+
+impl Env {
+    /// The environment of the instance `instance_id` of `spec`, its calls are run in a new lane.
+    pub fn new(
+        instance_id: u64,
+        spec: &ModuleSpec,
+        channel: LogChannel,
+        outstanding: Arc<AtomicUsize>,
+        runtime: Handle,
+        kv: Option<KvAccess>,
+        http: Option<HttpClient>,
+    ) -> Self {
+        Self {
+            name: Default::default(),
+            instance_id,
+            lane: Default::default(),
+            name_ptr: Default::default(),
+            memory: Default::default(),
+            malloc: Default::default(),
+            free: Default::default(),
+            resolve: Default::default(),
+            remaining_points: Default::default(),
+            points_exhausted: Default::default(),
+            channel,
+            log_level: spec.log_level,
+            config: Arc::new(spec.config.clone()),
+            outstanding,
+            runtime,
+            kv,
+            http,
+        }
+    }
+
+    pub fn instance_id(&self) -> u64 {
+        self.instance_id
+    }
+
+    pub fn lane(&self) -> &Arc<Lane> {
+        &self.lane
+    }
+
+    pub fn memory(&self) -> std::result::Result<GuestMemory<'_>, Error> {
+        self.memory
+            .get_ref()
+            .map(GuestMemory::new)
+            .ok_or_else(|| ExportError::Missing("memory".to_string()).into())
+    }
+
+    pub fn malloc(&self, size: u32) -> std::result::Result<GuestPtr<u8>, Error> {
+        let malloc = self
+            .malloc
+            .get_ref()
+            .ok_or_else(|| ExportError::Missing("_wasm_malloc".to_string()))?;
+        match malloc.call(size as i32)? {
+            0 => Err(Error::OutOfMemory(size)),
+            ptr => Ok(GuestPtr::new(ptr as u32)),
+        }
+    }
+
+    pub fn free(&self, ptr: GuestPtr<u8>, size: u32) -> std::result::Result<(), Error> {
+        let free = self
+            .free
+            .get_ref()
+            .ok_or_else(|| ExportError::Missing("_wasm_free".to_string()))?;
+        Ok(free.call(ptr.offset() as i32, size as i32)?)
+    }
+
+    /// Copy `data` into memory allocated by the guest, the guest owns the returned slice.
+    pub fn alloc_bytes(&self, data: &[u8]) -> std::result::Result<GuestSlice<u8>, Error> {
+        let ptr = self.malloc(data.len() as u32)?;
+        if let Err(e) = self.memory()?.write_bytes(ptr, data) {
+            self.free(ptr, data.len() as u32)?;
+            return Err(e);
+        }
+        Ok(GuestSlice::new(ptr.offset(), data.len() as u32))
+    }
+
+    /// Reply `response` to the guest's pending request `request_id` in the lane of the instance,
+    /// unless it was terminated since. `what` names the request in errors.
+    pub fn post_reply<W>(&self, request_id: u64, response: std::result::Result<Vec<u8>, Error>, what: W)
+    where
+        W: fmt::Display + Send + 'static,
+    {
+        let env = self.clone();
+        self.lane.post(move || {
+            if !GLOBAL_INSTANCE_MAP.contains_key(&env.instance_id) {
+                return debug!("dropping {} reply, #{} is terminated", what, env.instance_id);
+            }
+            match encode_reply(&response).and_then(|data| env.reply(request_id, &data)) {
+                Ok(()) => {}
+                // the guest dropped the task waiting for it, such as a cancelled call
+                Err(Error::UnknownRequest(_)) => debug!("<{}> dropped {} request {}", env.name().unwrap_or("???"), what, request_id),
+                Err(e) => error!("cannot reply {} request for <{}>: {}", what, env.name().unwrap_or("???"), e),
+            }
+        });
+    }
+
+    /// Copy `data` into guest memory and resolve the guest's pending request `request_id` with it, in the lane.
+    fn reply(&self, request_id: u64, data: &[u8]) -> std::result::Result<(), Error> {
+        let resolve = self
+            .resolve
+            .get_ref()
+            .ok_or_else(|| ExportError::Missing("_wasm_resolve".to_string()))?;
+        // a null reply tells the guest we could not allocate the reply in its memory
+        let slice = match self.alloc_bytes(data) {
+            Err(Error::OutOfMemory(_)) => GuestSlice::new(0, 0),
+            slice => slice?,
+        };
+        let call = || resolve.call(request_id as i64, slice.ptr().offset() as i32, slice.len() as i32);
+        let ret = match (self.remaining_points.get_ref(), self.points_exhausted.get_ref()) {
+            (Some(remaining), Some(exhausted)) => {
+                let points = metering::Points::new(remaining.clone(), exhausted.clone());
+                metering::metered(&points, self.instance_id(), None, call).0
+            }
+            _ => call().map_err(|e| e.into()),
+        };
+        self.free(slice.ptr(), slice.len())?;
+        match ret? {
+            0 => Err(Error::UnknownRequest(request_id)),
+            _ => Ok(()),
+        }
+    }
+
+    /// The key-value namespace of the guest.
+    pub fn kv(&self) -> std::result::Result<Namespace, Error> {
+        let kv = self.kv.as_ref().ok_or_else(|| Error::Kv("no key-value store".to_string()))?;
+        Ok(kv.namespace())
+    }
+
+    #[inline]
+    pub fn name(&self) -> Option<&str> {
+        self.name
+            .get_or_init(|| {
+                let offset = self.name_ptr.get_ref()?.get().i32()? as u32;
+                read_name(&self.memory().ok()?, offset).ok()
+            })
+            .as_ref()
+            .map(|s| s.as_str())
+    }
+}
+
+/// Read the `NAME: &CStr` a guest exports, `offset` is the address of the `&CStr`.
+fn read_name(memory: &GuestMemory, offset: u32) -> std::result::Result<String, Error> {
+    let name_ptr = memory.read(GuestPtr::<u32>::new(offset))?;
+    memory.read_c_str(GuestPtr::new(name_ptr))
+}
+
+pub fn instance_name(instance: &Instance) -> Option<String> {
+    let offset = instance.exports.get_global("NAME").ok()?.get().i32()? as u32;
+    let memory = instance.exports.get_memory("memory").ok()?;
+    read_name(&GuestMemory::new(memory), offset).ok()
+}
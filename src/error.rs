@@ -1,6 +1,121 @@
+use we_proto::{ErrorCode, RemoteError, Response};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("ser/de error {0}")]
-    Bincode(#[from] bincode::Error)
-}
\ No newline at end of file
+    Bincode(#[from] bincode::Error),
+    #[error("{0}")]
+    Codec(#[from] we_codec::Error),
+    #[error("runtime error {0}")]
+    Runtime(wasmer::RuntimeError),
+    #[error("export error {0}")]
+    Export(#[from] wasmer::ExportError),
+    #[error("compile error {0}")]
+    Compile(#[from] wasmer::CompileError),
+    #[error("instantiation error {0}")]
+    Instantiation(Box<wasmer::InstantiationError>),
+    #[error("abi error: {0}")]
+    Abi(String),
+    #[error("service not found: {0}::{1}")]
+    ServiceNotFound(String, String),
+    #[error("guest memory access out of bounds at {offset:#x}+{len}")]
+    OutOfBounds { offset: u32, len: usize },
+    #[error("guest out of memory allocating {0} bytes")]
+    OutOfMemory(u32),
+    #[error("invalid utf-8 from guest: {0}")]
+    Utf8(#[from] std::string::FromUtf8Error),
+    #[error("no pending request {0}")]
+    UnknownRequest(u64),
+    #[error("out of fuel, limit is {0}")]
+    OutOfFuel(u64),
+    #[error("no instance #{0}")]
+    InstanceNotFound(u64),
+    #[error("instance #{0} is terminated")]
+    Terminated(u64),
+    #[error("call timed out")]
+    Timeout,
+    #[error("call cancelled")]
+    Cancelled,
+    #[error("log channel closed")]
+    LogChannelClosed,
+    #[error("wasi error: {0}")]
+    Wasi(String),
+    #[error("kv error: {0}")]
+    Kv(String),
+    #[error("http error: {0}")]
+    Http(String),
+    #[error("http request to {0} is not allowed")]
+    HttpDenied(String),
+    #[error("http response body exceeds {0} bytes")]
+    HttpBodyTooLarge(usize),
+    #[error("every instance of <{0}> is busy")]
+    Busy(String),
+    #[error("remote error {code}: {message}")]
+    Remote { code: ErrorCode, message: String },
+}
+
+impl Error {
+    /// Code of the error, as guests see it in `we_rt::error::Error::Remote`.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::Bincode(_) | Error::Codec(_) => ErrorCode::Codec,
+            Error::Runtime(_) => ErrorCode::Runtime,
+            Error::Export(_) => ErrorCode::Export,
+            Error::Compile(_) => ErrorCode::Compile,
+            Error::Instantiation(_) => ErrorCode::Instantiation,
+            Error::Abi(_) => ErrorCode::Abi,
+            Error::ServiceNotFound(..) => ErrorCode::ServiceNotFound,
+            Error::OutOfBounds { .. } => ErrorCode::OutOfBounds,
+            Error::OutOfMemory(_) => ErrorCode::OutOfMemory,
+            Error::Utf8(_) => ErrorCode::Utf8,
+            Error::UnknownRequest(_) => ErrorCode::UnknownRequest,
+            Error::OutOfFuel(_) => ErrorCode::OutOfFuel,
+            Error::InstanceNotFound(_) => ErrorCode::InstanceNotFound,
+            Error::Terminated(_) => ErrorCode::Terminated,
+            Error::Timeout => ErrorCode::Timeout,
+            Error::Cancelled => ErrorCode::Cancelled,
+            Error::LogChannelClosed => ErrorCode::LogChannelClosed,
+            Error::Wasi(_) => ErrorCode::Wasi,
+            Error::Kv(_) => ErrorCode::Kv,
+            Error::Http(_) => ErrorCode::Http,
+            Error::HttpDenied(_) => ErrorCode::HttpDenied,
+            Error::HttpBodyTooLarge(_) => ErrorCode::HttpBodyTooLarge,
+            Error::Busy(_) => ErrorCode::Busy,
+            Error::Remote { code, .. } => *code,
+        }
+    }
+}
+
+impl From<wasmer::InstantiationError> for Error {
+    fn from(e: wasmer::InstantiationError) -> Self {
+        Error::Instantiation(Box::new(e))
+    }
+}
+
+/// Traps raised by host imports carry an `Error`, recover it instead of wrapping the trap.
+impl From<wasmer::RuntimeError> for Error {
+    fn from(e: wasmer::RuntimeError) -> Self {
+        match e.downcast::<Error>() {
+            Ok(e) => e,
+            Err(e) => Error::Runtime(e),
+        }
+    }
+}
+
+/// Encode the outcome of a call as the reply guests expect on `_wasm_resolve`.
+pub fn encode_reply(result: &Result<Vec<u8>, Error>) -> Result<Vec<u8>, Error> {
+    let response = match result {
+        Ok(data) => Response::Ok(data),
+        Err(Error::Remote { code, message }) => Response::Err(RemoteError { code: *code, message: message.clone() }),
+        Err(e) => Response::Err(RemoteError { code: e.code(), message: e.to_string() }),
+    };
+    Ok(response.encode())
+}
+
+/// Decode a reply a guest passed to `callback`, guest errors become `Error::Remote`.
+pub fn decode_reply(data: &[u8]) -> Result<Vec<u8>, Error> {
+    match Response::decode(data)? {
+        Response::Ok(data) => Ok(data.to_vec()),
+        Response::Err(RemoteError { code, message }) => Err(Error::Remote { code, message }),
+    }
+}
@@ -0,0 +1,203 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::body::HttpBody;
+use hyper::header::{HeaderMap, HeaderValue, ALLOW, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use wasmer::{Function, Instance};
+use we_codec::Format;
+use we_proto::abi::{EXPORT_WITHOUT_ARGS, EXPORT_WITH_ARGS};
+use we_proto::ErrorCode;
+
+use crate::abi;
+use crate::error::Error;
+use crate::manager::InstanceManager;
+use crate::manifest::GatewaySpec;
+use crate::pool::LEASE_TIMEOUT;
+use crate::scheduler::WasmFunctionExecution;
+use crate::service::GLOBAL_SERVICE_REGISTRY;
+
+/// Status and message answered instead of a guest reply.
+type Rejection = (StatusCode, String);
+
+/// Serves `POST /{module}/{function}` by calling `function` on an instance of the service `module`.
+///
+/// The request body holds the arguments in the format of its content-type, see `Format::from_content_type`,
+/// and the reply of the guest is answered in the same format.
+struct Gateway {
+    manager: Arc<InstanceManager>,
+    max_body_bytes: usize,
+    timeout: Option<Duration>,
+    fuel: Option<u64>,
+}
+
+/// Bind `spec.listen` and serve the gateway on the current runtime.
+pub fn serve(spec: &GatewaySpec, manager: Arc<InstanceManager>) -> Result<(), hyper::Error> {
+    let server = Server::try_bind(&spec.listen)?;
+    let gateway = Arc::new(Gateway {
+        manager,
+        max_body_bytes: spec.max_body_bytes,
+        timeout: spec.timeout_ms.map(Duration::from_millis),
+        fuel: spec.fuel,
+    });
+    let make_service = make_service_fn(move |_| {
+        let gateway = gateway.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |request| gateway.clone().handle(request))) }
+    });
+    info!("gateway listening on {}", spec.listen);
+    tokio::spawn(async move {
+        if let Err(e) = server.serve(make_service).await {
+            error!("gateway stopped: {}", e);
+        }
+    });
+    Ok(())
+}
+
+impl Gateway {
+    async fn handle(self: Arc<Self>, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let response = match self.call(request).await {
+            Ok((format, reply)) => response(StatusCode::OK, format.content_type(), reply),
+            Err((status, message)) => {
+                let mut response = response(status, "text/plain; charset=utf-8", message.into_bytes());
+                if status == StatusCode::METHOD_NOT_ALLOWED {
+                    response.headers_mut().insert(ALLOW, HeaderValue::from_static("POST"));
+                }
+                response
+            }
+        };
+        Ok(response)
+    }
+
+    async fn call(&self, request: Request<Body>) -> Result<(Format, Vec<u8>), Rejection> {
+        if request.method() != Method::POST {
+            return Err((StatusCode::METHOD_NOT_ALLOWED, "exports are called with POST".to_string()));
+        }
+        let (module, function) = route(request.uri().path())
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "expected /{module}/{function}".to_string()))?;
+        let format = request_format(request.headers())?;
+        let args = read_body(request.into_body(), self.max_body_bytes).await?;
+
+        debug!("gateway call <{}>::{} with {} bytes of {:?}", module, function, args.len(), format);
+        // the lease keeps the instance busy until the guest replied, a full pool answers 503 once waiting timed out
+        let pool = GLOBAL_SERVICE_REGISTRY
+            .pool_serving(&module)
+            .ok_or_else(|| (StatusCode::NOT_FOUND, format!("no module <{}>", module)))?;
+        let lease = pool.acquire(None, LEASE_TIMEOUT).await.map_err(rejection)?;
+        let reply = {
+            let instance = self.manager.instance(lease.instance_id()).map_err(rejection)?;
+            let (export, takes_args) = export(&instance, &function)?;
+            let mut execution = WasmFunctionExecution::<()>::new(lease.instance_id(), &instance, export).with_format(format);
+            if let Some(timeout) = self.timeout {
+                execution = execution.with_timeout(timeout);
+            }
+            if let Some(fuel) = self.fuel {
+                execution = execution.with_fuel(fuel);
+            }
+            if takes_args {
+                execution.call_bytes_with(&args)
+            } else {
+                execution.call_bytes()
+            }
+        };
+        let reply = reply.await.map_err(rejection)?;
+        drop(lease);
+        Ok((format, reply))
+    }
+}
+
+/// Split `/{module}/{function}` into its segments.
+fn route(path: &str) -> Option<(String, String)> {
+    let mut segments = path.trim_start_matches('/').splitn(2, '/');
+    match (segments.next(), segments.next()) {
+        (Some(module), Some(function)) if !module.is_empty() && !function.is_empty() && !function.contains('/') => {
+            Some((module.to_string(), function.to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// The guest export `name` and whether it takes arguments.
+///
+/// Exports of the runtime itself start with `_` or have another signature, they cannot be called.
+fn export<'a>(instance: &'a Instance, name: &str) -> Result<(&'a Function, bool), Rejection> {
+    let not_found = || (StatusCode::NOT_FOUND, format!("no export {}", name));
+    if name.starts_with('_') {
+        return Err(not_found());
+    }
+    let function = instance.exports.get_function(name).map_err(|_| not_found())?;
+    let ty = function.ty();
+    if abi::is_procedure(ty, EXPORT_WITH_ARGS) {
+        Ok((function, true))
+    } else if abi::is_procedure(ty, EXPORT_WITHOUT_ARGS) {
+        Ok((function, false))
+    } else {
+        Err(not_found())
+    }
+}
+
+/// Format of the arguments by content-type, bincode if there is none.
+fn request_format(headers: &HeaderMap) -> Result<Format, Rejection> {
+    let content_type = match headers.get(CONTENT_TYPE) {
+        Some(value) => String::from_utf8_lossy(value.as_bytes()).into_owned(),
+        None => return Ok(Format::Bincode),
+    };
+    Format::from_content_type(&content_type)
+        .ok_or_else(|| (StatusCode::UNSUPPORTED_MEDIA_TYPE, format!("unsupported content-type {}", content_type)))
+}
+
+/// Read `body` in chunks, refused once it exceeds `limit` bytes.
+async fn read_body(mut body: Body, limit: usize) -> Result<Vec<u8>, Rejection> {
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        if data.len() + chunk.len() > limit {
+            return Err((StatusCode::PAYLOAD_TOO_LARGE, format!("request body exceeds {} bytes", limit)));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+fn rejection(e: Error) -> Rejection {
+    let status = match e {
+        Error::InstanceNotFound(_) => StatusCode::NOT_FOUND,
+        Error::Terminated(_) | Error::Cancelled | Error::Busy(_) => StatusCode::SERVICE_UNAVAILABLE,
+        Error::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        // the guest does not support the format of the request
+        Error::Abi(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        // the guest could not decode the arguments
+        Error::Remote { code: ErrorCode::Codec, .. } => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, e.to_string())
+}
+
+fn response(status: StatusCode, content_type: &'static str, body: Vec<u8>) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_module_and_function() {
+        assert_eq!(route("/hello/add_one"), Some(("hello".to_string(), "add_one".to_string())));
+        assert_eq!(route("/hello"), None);
+        assert_eq!(route("/hello/"), None);
+        assert_eq!(route("//add_one"), None);
+        assert_eq!(route("/hello/add/one"), None);
+    }
+
+    #[test]
+    fn busy_pools_are_unavailable() {
+        assert_eq!(rejection(Error::Busy("hello".to_string())).0, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(rejection(Error::Timeout).0, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(rejection(Error::InstanceNotFound(1)).0, StatusCode::NOT_FOUND);
+    }
+}
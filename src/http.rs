@@ -0,0 +1,237 @@
+use std::time::Duration;
+
+use reqwest::{Method, Url};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::manifest::HttpSpec;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Request a guest passes to `http_fetch`, see `we_rt::http::Request`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Request {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Response replied to `http_fetch`, see `we_rt::http::Response`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// A client shared by all modules.
+///
+/// Redirects are not followed, a redirect to a host outside the allow-list would escape it,
+/// guests see the redirect response instead.
+pub fn client() -> Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .map_err(http_error)
+}
+
+/// Outbound HTTP of one module, restricted to the hosts of its allow-list.
+#[derive(Clone)]
+pub struct HttpClient {
+    client: reqwest::Client,
+    /// Host patterns with the port they are restricted to
+    allow: Vec<(String, Option<u16>)>,
+    max_body_bytes: usize,
+    timeout: Option<Duration>,
+}
+
+impl HttpClient {
+    pub fn new(client: reqwest::Client, spec: &HttpSpec) -> Self {
+        Self {
+            client,
+            allow: spec.allow.iter().map(|entry| parse_allowed(&entry.to_lowercase())).collect(),
+            max_body_bytes: spec.max_body_bytes,
+            timeout: spec.timeout_ms.map(Duration::from_millis),
+        }
+    }
+
+    /// Whether `url` is an http(s) URL whose host matches an entry of the allow-list.
+    pub fn is_allowed(&self, url: &Url) -> bool {
+        let (host, port) = match (url.scheme(), url.host_str(), url.port_or_known_default()) {
+            ("http", Some(host), Some(port)) | ("https", Some(host), Some(port)) => (host.to_lowercase(), port),
+            _ => return false,
+        };
+        self.allow.iter().any(|(pattern, allowed_port)| {
+            let host_matches = match pattern.strip_prefix("*.") {
+                Some(domain) => host.ends_with(&format!(".{}", domain)),
+                None => host == *pattern,
+            };
+            // entries without a port allow any
+            host_matches && !allowed_port.is_some_and(|allowed_port| allowed_port != port)
+        })
+    }
+
+    /// Perform `request`, the body is read in chunks and refused with `Error::HttpBodyTooLarge`
+    /// once it exceeds `max_body_bytes`.
+    pub async fn fetch(&self, request: Request) -> Result<Response> {
+        let method = Method::from_bytes(request.method.as_bytes()).map_err(|e| Error::Http(e.to_string()))?;
+        let url = Url::parse(&request.url).map_err(|e| Error::Http(e.to_string()))?;
+        if !self.is_allowed(&url) {
+            return Err(Error::HttpDenied(url.to_string()));
+        }
+
+        let mut builder = self.client.request(method, url).body(request.body);
+        for (name, value) in request.headers {
+            builder = builder.header(name, value);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        let mut response = builder.send().await.map_err(http_error)?;
+
+        let status = response.status().as_u16();
+        let headers = response
+            .headers()
+            .iter()
+            .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+            .collect();
+        if response.content_length().is_some_and(|len| len > self.max_body_bytes as u64) {
+            return Err(Error::HttpBodyTooLarge(self.max_body_bytes));
+        }
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(http_error)? {
+            if body.len() + chunk.len() > self.max_body_bytes {
+                return Err(Error::HttpBodyTooLarge(self.max_body_bytes));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(Response { status, headers, body })
+    }
+}
+
+/// Split an allow-list entry into host pattern and port, IPv6 addresses are written in brackets.
+fn parse_allowed(entry: &str) -> (String, Option<u16>) {
+    match entry.rsplit_once(':') {
+        Some((host, port)) if !entry.ends_with(']') => match port.parse() {
+            Ok(port) => (host.to_string(), Some(port)),
+            Err(_) => (entry.to_string(), None),
+        },
+        _ => (entry.to_string(), None),
+    }
+}
+
+fn http_error(e: reqwest::Error) -> Error {
+    Error::Http(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(allow: &[&str]) -> HttpClient {
+        let spec = HttpSpec::allow(allow.iter().map(|entry| entry.to_string()).collect());
+        HttpClient::new(reqwest::Client::new(), &spec)
+    }
+
+    fn allowed(client: &HttpClient, url: &str) -> bool {
+        client.is_allowed(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn parses_allowed_entries() {
+        assert_eq!(parse_allowed("example.com"), ("example.com".to_string(), None));
+        assert_eq!(parse_allowed("example.com:8080"), ("example.com".to_string(), Some(8080)));
+        assert_eq!(parse_allowed("*.example.com:443"), ("*.example.com".to_string(), Some(443)));
+        assert_eq!(parse_allowed("[::1]:8080"), ("[::1]".to_string(), Some(8080)));
+        assert_eq!(parse_allowed("[::1]"), ("[::1]".to_string(), None));
+        assert_eq!(parse_allowed("example.com:http"), ("example.com:http".to_string(), None));
+    }
+
+    #[test]
+    fn allows_listed_hosts_on_any_port() {
+        let client = client(&["Example.com"]);
+        assert!(allowed(&client, "https://example.com/path"));
+        assert!(allowed(&client, "http://EXAMPLE.com:8080/"));
+        assert!(!allowed(&client, "https://api.example.com/"));
+        assert!(!allowed(&client, "https://example.com.evil.net/"));
+        assert!(!allowed(&client, "ftp://example.com/"));
+    }
+
+    #[test]
+    fn restricts_ports() {
+        let client = client(&["example.com:8080", "[::1]:443"]);
+        assert!(allowed(&client, "http://example.com:8080/"));
+        assert!(!allowed(&client, "http://example.com/"));
+        assert!(!allowed(&client, "https://example.com/"));
+        assert!(allowed(&client, "https://[::1]/"));
+        assert!(!allowed(&client, "http://[::1]/"));
+    }
+
+    #[test]
+    fn wildcards_match_subdomains_only() {
+        let client = client(&["*.example.com"]);
+        assert!(allowed(&client, "https://api.example.com/"));
+        assert!(allowed(&client, "https://a.b.example.com/"));
+        assert!(!allowed(&client, "https://example.com/"));
+        assert!(!allowed(&client, "https://notexample.com/"));
+    }
+
+    #[test]
+    fn nothing_is_allowed_by_default() {
+        assert!(!allowed(&client(&[]), "https://example.com/"));
+    }
+
+    /// Serve `/sized` with a content-length and `/chunked` without one, both with a 64 bytes body.
+    fn serve() -> std::net::SocketAddr {
+        use hyper::service::{make_service_fn, service_fn};
+        use hyper::{Body, Server};
+
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, std::convert::Infallible>(service_fn(|request: hyper::Request<Body>| async move {
+                let body = match request.uri().path() {
+                    "/sized" => Body::from(vec![b'x'; 64]),
+                    _ => {
+                        let (mut sender, body) = Body::channel();
+                        tokio::spawn(async move {
+                            for _ in 0..8 {
+                                if sender.send_data(vec![b'x'; 8].into()).await.is_err() {
+                                    return;
+                                }
+                            }
+                        });
+                        body
+                    }
+                };
+                Ok::<_, std::convert::Infallible>(hyper::Response::new(body))
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn bodies_are_capped() {
+        let addr = serve();
+        let mut spec = HttpSpec::allow(vec![addr.to_string()]);
+        let get = |path: &str| Request {
+            method: "GET".to_string(),
+            url: format!("http://{}{}", addr, path),
+            headers: Vec::new(),
+            body: Vec::new(),
+        };
+
+        spec.max_body_bytes = 64;
+        let client = HttpClient::new(reqwest::Client::new(), &spec);
+        assert_eq!(client.fetch(get("/sized")).await.unwrap().body.len(), 64);
+        assert_eq!(client.fetch(get("/chunked")).await.unwrap().body.len(), 64);
+
+        spec.max_body_bytes = 63;
+        let client = HttpClient::new(reqwest::Client::new(), &spec);
+        for path in ["/sized", "/chunked"].iter() {
+            assert!(matches!(client.fetch(get(path)).await, Err(Error::HttpBodyTooLarge(63))), "{}", path);
+        }
+    }
+}
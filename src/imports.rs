@@ -0,0 +1,223 @@
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use wasmer::{imports, Function, ImportObject, Store};
+use we_codec::Format;
+use we_proto::Request;
+
+use crate::abi::IMPORT_MODULE;
+use crate::env::Env;
+use crate::error::Error;
+use crate::http;
+use crate::kv::Namespace;
+use crate::memory::{GuestPtr, GuestSlice};
+use crate::scheduler::PENDING_CALLS;
+use crate::service::GLOBAL_SERVICE_REGISTRY;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Origin of the monotonic clock guests read through `now`.
+static CLOCK_START: Lazy<Instant> = Lazy::new(Instant::now);
+
+pub fn import_object(store: &Store, env: Env) -> ImportObject {
+    imports! {
+        IMPORT_MODULE => {
+            "invoke" => Function::new_native_with_env(store, env.clone(), invoke),
+            "log_proxy" => Function::new_native_with_env(store, env.clone(), log_proxy),
+            "callback" => Function::new_native_with_env(store, env.clone(), callback),
+            "config" => Function::new_native_with_env(store, env.clone(), config),
+            "now" => Function::new_native(store, now),
+            "sleep" => Function::new_native_with_env(store, env.clone(), sleep),
+            "kv_get" => Function::new_native_with_env(store, env.clone(), kv_get),
+            "kv_put" => Function::new_native_with_env(store, env.clone(), kv_put),
+            "kv_delete" => Function::new_native_with_env(store, env.clone(), kv_delete),
+            "kv_scan_prefix" => Function::new_native_with_env(store, env.clone(), kv_scan_prefix),
+            "http_fetch" => Function::new_native_with_env(store, env, http_fetch)
+        }
+    }
+}
+
+fn callback(env: &Env, request_id: i64, ptr: i32, len: i32) -> Result<()> {
+    let data = env.memory()?.read_bytes(GuestSlice::new(ptr as u32, len as u32))?;
+    if !PENDING_CALLS.resolve(env.instance_id(), request_id as u64, data) {
+        warn!(
+            "<{}>#{} replied to unknown request {}",
+            env.name().unwrap_or("???"),
+            env.instance_id(),
+            request_id
+        );
+    }
+    Ok(())
+}
+
+/// Dispatch the `Request` envelope at `request_ptr`, the reply goes to the guest's request `request_id`.
+fn invoke(env: &Env, request_ptr: i32, request_len: i32, request_id: i64) -> Result<()> {
+    let data = env.memory()?.read_bytes(GuestSlice::new(request_ptr as u32, request_len as u32))?;
+    let request = match Request::decode(&data) {
+        Ok(request) => request,
+        Err(e) => {
+            env.post_reply(request_id as u64, Err(e.into()), "invoke");
+            return Ok(());
+        }
+    };
+
+    debug!(
+        "request from <{}>#{}, {}::{}",
+        env.name().unwrap_or("???"),
+        env.instance_id(),
+        request.service,
+        request.method,
+    );
+
+    let (env, service) = (env.clone(), format!("{}::{}", request.service, request.method));
+    let caller = Some(env.instance_id());
+    env.outstanding.fetch_add(1, Ordering::SeqCst);
+    let respond = move |response| {
+        env.outstanding.fetch_sub(1, Ordering::SeqCst);
+        env.post_reply(request_id as u64, response, service);
+    };
+    GLOBAL_SERVICE_REGISTRY.dispatch(caller, request.service, request.method, request.format, request.args, respond);
+    Ok(())
+}
+
+fn log_proxy(env: &Env, record_ptr: i32, record_len: i32, format: i32) -> Result<()> {
+    let format = Format::from_tag(format as u32)?;
+    let record_serialized = env.memory()?.read_bytes(GuestSlice::new(record_ptr as u32, record_len as u32))?;
+    let name = env.name().unwrap_or("???").to_string();
+    env.channel
+        .send((name, env.log_level, format, record_serialized))
+        .map_err(|_| Error::LogChannelClosed)
+}
+
+/// Look up a config value, on success the guest owned `(ptr, len)` of the value is written to `ret_ptr`.
+fn config(env: &Env, key_ptr: i32, key_len: i32, ret_ptr: i32) -> Result<i32> {
+    let key = env.memory()?.read_str(GuestSlice::new(key_ptr as u32, key_len as u32))?;
+    let value = match env.config.get(&key) {
+        Some(value) => value,
+        None => return Ok(0),
+    };
+    let slice = env.alloc_bytes(value.as_bytes())?;
+    let memory = env.memory()?;
+    memory.write(GuestPtr::<u32>::new(ret_ptr as u32), slice.ptr().offset())?;
+    memory.write(GuestPtr::<u32>::new(ret_ptr as u32 + 4), slice.len())?;
+    Ok(1)
+}
+
+/// Nanoseconds elapsed on a monotonic clock shared by all instances.
+fn now() -> i64 {
+    CLOCK_START.elapsed().as_nanos() as i64
+}
+
+/// Post an empty reply to the guest's pending request `request_id` once `nanos` elapsed.
+fn sleep(env: &Env, nanos: i64, request_id: i64) -> Result<()> {
+    let env = env.clone();
+    let duration = Duration::from_nanos(nanos.max(0) as u64);
+    env.runtime.clone().spawn(async move {
+        tokio::time::sleep(duration).await;
+        env.post_reply(request_id as u64, Ok(Vec::new()), "sleep");
+    });
+    Ok(())
+}
+
+fn kv_get(env: &Env, key_ptr: i32, key_len: i32, request_id: i64) -> Result<()> {
+    let key = env.memory()?.read_bytes(GuestSlice::new(key_ptr as u32, key_len as u32))?;
+    kv_request(env, request_id, move |kv| kv.get(&key))
+}
+
+fn kv_put(env: &Env, key_ptr: i32, key_len: i32, value_ptr: i32, value_len: i32, request_id: i64) -> Result<()> {
+    let memory = env.memory()?;
+    let key = memory.read_bytes(GuestSlice::new(key_ptr as u32, key_len as u32))?;
+    let value = memory.read_bytes(GuestSlice::new(value_ptr as u32, value_len as u32))?;
+    kv_request(env, request_id, move |kv| kv.put(&key, &value))
+}
+
+fn kv_delete(env: &Env, key_ptr: i32, key_len: i32, request_id: i64) -> Result<()> {
+    let key = env.memory()?.read_bytes(GuestSlice::new(key_ptr as u32, key_len as u32))?;
+    kv_request(env, request_id, move |kv| kv.delete(&key))
+}
+
+fn kv_scan_prefix(env: &Env, prefix_ptr: i32, prefix_len: i32, request_id: i64) -> Result<()> {
+    let prefix = env.memory()?.read_bytes(GuestSlice::new(prefix_ptr as u32, prefix_len as u32))?;
+    kv_request(env, request_id, move |kv| kv.scan_prefix(&prefix))
+}
+
+/// Run `op` on the namespace of the guest off the runtime threads, backends may block on disk,
+/// and post its bincode encoded outcome to the guest's request `request_id`.
+fn kv_request<R, F>(env: &Env, request_id: i64, op: F) -> Result<()>
+where
+    R: Serialize,
+    F: FnOnce(Namespace) -> Result<R> + Send + 'static,
+{
+    let kv = env.kv()?;
+    let env = env.clone();
+    env.outstanding.fetch_add(1, Ordering::SeqCst);
+    env.runtime.clone().spawn_blocking(move || {
+        let response = op(kv).and_then(|result| Ok(bincode::serialize(&result)?));
+        env.outstanding.fetch_sub(1, Ordering::SeqCst);
+        env.post_reply(request_id as u64, response, "kv");
+    });
+    Ok(())
+}
+
+/// Fetch the bincode encoded `we_rt::http::Request` at `request_ptr` on the runtime,
+/// the response or the refusal of the allow-list is posted to the guest's request `request_id`.
+fn http_fetch(env: &Env, request_ptr: i32, request_len: i32, request_id: i64) -> Result<()> {
+    let data = env.memory()?.read_bytes(GuestSlice::new(request_ptr as u32, request_len as u32))?;
+    let request: http::Request = match bincode::deserialize(&data) {
+        Ok(request) => request,
+        Err(e) => {
+            env.post_reply(request_id as u64, Err(e.into()), "http");
+            return Ok(());
+        }
+    };
+    let env = env.clone();
+    env.outstanding.fetch_add(1, Ordering::SeqCst);
+    env.runtime.clone().spawn(async move {
+        let response = match env.http {
+            Some(ref http) => http.fetch(request).await,
+            None => Err(Error::HttpDenied(request.url)),
+        };
+        let response = response.and_then(|response| Ok(bincode::serialize(&response)?));
+        env.outstanding.fetch_sub(1, Ordering::SeqCst);
+        env.post_reply(request_id as u64, response, "http");
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use we_proto::ErrorCode;
+
+    use super::*;
+    use crate::manifest::Manifest;
+    use crate::scheduler::WasmFunctionExecution;
+    use crate::testing::{guest, manager, spec};
+
+    /// `fetch` passes its arguments to `http_fetch` as they are, `_wasm_resolve` replies the response.
+    const FETCH: &str = r#"
+        (import "__wasm_everything_runtime__" "http_fetch" (func $http_fetch (param i32 i32 i64)))
+        (import "__wasm_everything_runtime__" "callback" (func $callback (param i64 i32 i32)))
+        (func (export "fetch") (param $ptr i32) (param $len i32) (param $id i64)
+            (call $http_fetch (local.get $ptr) (local.get $len) (local.get $id)))
+        (func (export "_wasm_resolve") (param $id i64) (param $ptr i32) (param $len i32) (result i32)
+            (call $callback (local.get $id) (local.get $ptr) (local.get $len))
+            (i32.const 1))
+    "#;
+
+    #[tokio::test]
+    async fn malformed_fetches_are_replied_not_trapped() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager();
+        let spec = spec(dir.path(), "fetcher", &guest(0, FETCH));
+        let id = manager.deploy(&Manifest { modules: vec![spec], ..Default::default() }).unwrap()[0];
+        let instance = manager.instance(id).unwrap();
+        let fetch = instance.exports.get_function("fetch").unwrap();
+
+        for _ in 0..2 {
+            let reply = WasmFunctionExecution::<()>::new(id, &instance, fetch).call_bytes_with(b"\x01").await;
+            assert!(matches!(reply, Err(Error::Remote { code: ErrorCode::Codec, .. })), "{:?}", reply);
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::error::Error;
+use crate::manifest::KvSpec;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Storage behind the key-value store guests reach through `we_rt::kv`.
+///
+/// Backends see the full keys, modules are kept apart by `Namespace`.
+pub trait KvBackend: Send + Sync {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()>;
+    /// Returns whether `key` existed.
+    fn delete(&self, key: &[u8]) -> Result<bool>;
+    /// Pairs whose key starts with `prefix`, in key order.
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+}
+
+/// Open the backend `spec` describes.
+pub fn open(spec: &KvSpec) -> anyhow::Result<Arc<dyn KvBackend>> {
+    Ok(match spec {
+        KvSpec::Memory => Arc::new(MemoryBackend::default()),
+        KvSpec::Sled { path } => Arc::new(SledBackend::open(path)?),
+    })
+}
+
+#[derive(Default)]
+pub struct MemoryBackend {
+    entries: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl KvBackend for MemoryBackend {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.entries.lock().unwrap().get(key).cloned())
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.entries.lock().unwrap().insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.entries.lock().unwrap().remove(key).is_some())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let entries = self.entries.lock().unwrap();
+        Ok(entries
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+}
+
+/// On-disk backend, writes are durable once sled flushed them, by default every 500ms.
+pub struct SledBackend {
+    db: sled::Db,
+}
+
+impl SledBackend {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self { db: sled::open(path).map_err(kv_error)? })
+    }
+}
+
+impl KvBackend for SledBackend {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.db.get(key).map_err(kv_error)?.map(|value| value.to_vec()))
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.db.insert(key, value).map_err(kv_error)?;
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.db.remove(key).map_err(kv_error)?.is_some())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.db
+            .scan_prefix(prefix)
+            .map(|entry| entry.map(|(key, value)| (key.to_vec(), value.to_vec())).map_err(kv_error))
+            .collect()
+    }
+}
+
+fn kv_error(e: sled::Error) -> Error {
+    Error::Kv(e.to_string())
+}
+
+/// The keys of one module in a backend, keys outside of it cannot be named.
+#[derive(Clone)]
+pub struct Namespace {
+    backend: Arc<dyn KvBackend>,
+    /// Length prefixed name, so no namespace is a prefix of another
+    prefix: Vec<u8>,
+}
+
+impl Namespace {
+    pub fn new(backend: Arc<dyn KvBackend>, name: &str) -> Self {
+        let mut prefix = (name.len() as u32).to_be_bytes().to_vec();
+        prefix.extend_from_slice(name.as_bytes());
+        Self { backend, prefix }
+    }
+
+    fn key(&self, key: &[u8]) -> Vec<u8> {
+        [self.prefix.as_slice(), key].concat()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.backend.get(&self.key(key))
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.backend.put(&self.key(key), value)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<bool> {
+        self.backend.delete(&self.key(key))
+    }
+
+    /// Pairs whose key starts with `prefix`, keys are returned without the namespace.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let entries = self.backend.scan_prefix(&self.key(prefix))?;
+        Ok(entries
+            .into_iter()
+            .map(|(key, value)| (key[self.prefix.len()..].to_vec(), value))
+            .collect())
+    }
+}
+
+/// The key-value store of a module, in the namespace the host assigned it, see `ModuleSpec::namespace`.
+#[derive(Clone)]
+pub struct KvAccess {
+    backend: Arc<dyn KvBackend>,
+    namespace: String,
+}
+
+impl KvAccess {
+    pub fn new(backend: Arc<dyn KvBackend>, namespace: String) -> Self {
+        Self { backend, namespace }
+    }
+
+    pub fn namespace(&self) -> Namespace {
+        Namespace::new(self.backend.clone(), &self.namespace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn namespaces_are_isolated(backend: Arc<dyn KvBackend>) {
+        // neither namespace is a prefix of the other once encoded
+        let (a, ab) = (Namespace::new(backend.clone(), "a"), Namespace::new(backend, "ab"));
+        a.put(b"bkey", b"from a").unwrap();
+        ab.put(b"key", b"from ab").unwrap();
+        a.put(b"key", b"from a").unwrap();
+
+        assert_eq!(a.get(b"key").unwrap().as_deref(), Some(&b"from a"[..]));
+        assert_eq!(ab.get(b"key").unwrap().as_deref(), Some(&b"from ab"[..]));
+        assert_eq!(ab.get(b"bkey").unwrap(), None);
+        assert_eq!(
+            a.scan_prefix(b"").unwrap(),
+            vec![(b"bkey".to_vec(), b"from a".to_vec()), (b"key".to_vec(), b"from a".to_vec())]
+        );
+        assert_eq!(ab.scan_prefix(b"k").unwrap(), vec![(b"key".to_vec(), b"from ab".to_vec())]);
+
+        assert!(ab.delete(b"key").unwrap());
+        assert!(!ab.delete(b"bkey").unwrap());
+        assert_eq!(a.get(b"key").unwrap().as_deref(), Some(&b"from a"[..]));
+    }
+
+    #[test]
+    fn memory_namespaces_are_isolated() {
+        namespaces_are_isolated(open(&KvSpec::Memory).unwrap());
+    }
+
+    #[test]
+    fn sled_namespaces_are_isolated() {
+        let dir = tempfile::tempdir().unwrap();
+        namespaces_are_isolated(open(&KvSpec::Sled { path: dir.path().join("kv") }).unwrap());
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+
+use chashmap::CHashMap;
+use once_cell::sync::Lazy;
+
+/// Lane of every running instance, by instance id.
+pub static LANES: Lazy<CHashMap<u64, Arc<Lane>>> = Lazy::new(CHashMap::new);
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct Queue {
+    jobs: VecDeque<Job>,
+    running: bool,
+}
+
+/// Runs the calls into one instance one at a time, a guest never runs on two threads at once.
+///
+/// Every call into the guest after it was spawned, exports, replies, cancellations and the buffers
+/// they allocate, is a job posted to its lane. The thread finding the lane idle runs the queued jobs,
+/// the others return right away, so no thread ever waits for a lane while running a guest.
+#[derive(Default)]
+pub struct Lane {
+    queue: Mutex<Queue>,
+    idle: Condvar,
+}
+
+impl Lane {
+    /// Run `job` in the lane, now if it is idle or after the jobs queued before it.
+    ///
+    /// Jobs posted from a job of the same lane run once it returned, they never nest.
+    pub fn post<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        {
+            let mut queue = self.queue.lock().unwrap();
+            queue.jobs.push_back(Box::new(job));
+            if queue.running {
+                return;
+            }
+            queue.running = true;
+        }
+        self.drain();
+    }
+
+    /// Run `f` in the lane and wait for its result, blocking while another thread runs the lane.
+    ///
+    /// Only for instances being spawned, a job of this lane calling it would wait for itself.
+    pub fn enter<R>(&self, f: impl FnOnce() -> R) -> R {
+        {
+            let mut queue = self.queue.lock().unwrap();
+            while queue.running {
+                queue = self.idle.wait(queue).unwrap();
+            }
+            queue.running = true;
+        }
+        let result = {
+            let _unwind = Unwind(self);
+            f()
+        };
+        self.drain();
+        result
+    }
+
+    /// Run queued jobs until there are none left, then mark the lane idle. The caller marked it running.
+    fn drain(&self) {
+        let _unwind = Unwind(self);
+        loop {
+            let job = {
+                let mut queue = self.queue.lock().unwrap();
+                match queue.jobs.pop_front() {
+                    Some(job) => job,
+                    None => {
+                        queue.running = false;
+                        self.idle.notify_all();
+                        return;
+                    }
+                }
+            };
+            job();
+        }
+    }
+}
+
+/// Marks the lane idle if a job panics, the jobs queued after it run on the next post.
+struct Unwind<'a>(&'a Lane);
+
+impl Drop for Unwind<'_> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.0.queue.lock().unwrap().running = false;
+            self.0.idle.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn jobs_never_overlap() {
+        let lane = Arc::new(Lane::default());
+        let inside = Arc::new(AtomicBool::new(false));
+        let ran = Arc::new(AtomicUsize::new(0));
+        let threads = (0..8)
+            .map(|_| {
+                let (lane, inside, ran) = (lane.clone(), inside.clone(), ran.clone());
+                thread::spawn(move || {
+                    for _ in 0..200 {
+                        let (inside, ran) = (inside.clone(), ran.clone());
+                        lane.post(move || {
+                            assert!(!inside.swap(true, Ordering::SeqCst), "two jobs ran at once");
+                            thread::yield_now();
+                            inside.store(false, Ordering::SeqCst);
+                            ran.fetch_add(1, Ordering::SeqCst);
+                        });
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // the lane is idle once every posting thread returned
+        assert_eq!(ran.load(Ordering::SeqCst), 8 * 200);
+    }
+
+    #[test]
+    fn jobs_posted_by_jobs_run_after_them() {
+        let lane = Arc::new(Lane::default());
+        let (sender, order) = mpsc::channel();
+        let inner = lane.clone();
+        lane.post(move || {
+            let nested = sender.clone();
+            inner.post(move || nested.send("nested").unwrap());
+            sender.send("outer").unwrap();
+        });
+        assert_eq!(order.try_iter().collect::<Vec<_>>(), ["outer", "nested"]);
+    }
+
+    #[test]
+    fn enter_runs_the_jobs_posted_meanwhile() {
+        let lane = Arc::new(Lane::default());
+        let (sender, order) = mpsc::channel();
+        let inner = lane.clone();
+        let result = lane.enter(|| {
+            let posted = sender.clone();
+            inner.post(move || posted.send("posted").unwrap());
+            sender.send("entered").unwrap();
+            42
+        });
+        assert_eq!(result, 42);
+        assert_eq!(order.try_iter().collect::<Vec<_>>(), ["entered", "posted"]);
+    }
+
+    #[test]
+    fn panicking_jobs_leave_the_lane_usable() {
+        let lane = Arc::new(Lane::default());
+        let panicking = lane.clone();
+        assert!(thread::spawn(move || panicking.post(|| panic!("job failed"))).join().is_err());
+        let (sender, ran) = mpsc::channel();
+        lane.post(move || sender.send(()).unwrap());
+        assert!(ran.try_recv().is_ok());
+    }
+}
@@ -0,0 +1,94 @@
+use std::ptr::NonNull;
+use std::sync::Arc;
+
+use wasmer::vm::{self, MemoryError, MemoryStyle, TableStyle, VMMemoryDefinition, VMTableDefinition};
+use wasmer::{BaseTunables, MemoryType, Pages, Store, TableType, Tunables};
+
+use crate::manifest::Limits;
+use crate::GLOBAL_INSTANCE_MAP;
+
+/// Tunables capping every linear memory at `limit` pages.
+///
+/// Memories without a maximum get `limit` as maximum, so `memory.grow` past it fails in the guest.
+pub struct LimitingTunables<T: Tunables> {
+    limit: Pages,
+    base: T,
+}
+
+impl<T: Tunables> LimitingTunables<T> {
+    pub fn new(base: T, limit: Pages) -> Self {
+        Self { limit, base }
+    }
+
+    fn adjust_memory(&self, requested: &MemoryType) -> MemoryType {
+        let mut adjusted = *requested;
+        adjusted.maximum = Some(requested.maximum.map_or(self.limit, |maximum| maximum.min(self.limit)));
+        adjusted
+    }
+
+    fn validate_memory(&self, ty: &MemoryType) -> Result<(), MemoryError> {
+        if ty.minimum > self.limit {
+            return Err(MemoryError::MinimumMemoryTooLarge { min_requested: ty.minimum, max_allowed: self.limit });
+        }
+        Ok(())
+    }
+}
+
+impl<T: Tunables> Tunables for LimitingTunables<T> {
+    fn memory_style(&self, memory: &MemoryType) -> MemoryStyle {
+        self.base.memory_style(&self.adjust_memory(memory))
+    }
+
+    fn table_style(&self, table: &TableType) -> TableStyle {
+        self.base.table_style(table)
+    }
+
+    fn create_host_memory(&self, ty: &MemoryType, style: &MemoryStyle) -> Result<Arc<dyn vm::Memory>, MemoryError> {
+        let adjusted = self.adjust_memory(ty);
+        self.validate_memory(&adjusted)?;
+        self.base.create_host_memory(&adjusted, style)
+    }
+
+    unsafe fn create_vm_memory(
+        &self,
+        ty: &MemoryType,
+        style: &MemoryStyle,
+        vm_definition_location: NonNull<VMMemoryDefinition>,
+    ) -> Result<Arc<dyn vm::Memory>, MemoryError> {
+        let adjusted = self.adjust_memory(ty);
+        self.validate_memory(&adjusted)?;
+        self.base.create_vm_memory(&adjusted, style, vm_definition_location)
+    }
+
+    fn create_host_table(&self, ty: &TableType, style: &TableStyle) -> Result<Arc<dyn vm::Table>, String> {
+        self.base.create_host_table(ty, style)
+    }
+
+    unsafe fn create_vm_table(
+        &self,
+        ty: &TableType,
+        style: &TableStyle,
+        vm_definition_location: NonNull<VMTableDefinition>,
+    ) -> Result<Arc<dyn vm::Table>, String> {
+        self.base.create_vm_table(ty, style, vm_definition_location)
+    }
+}
+
+/// A store sharing the engine of `store`, enforcing `limits` on the modules compiled in it.
+pub fn store(store: &Store, limits: &Limits) -> Store {
+    match limits.memory_pages {
+        Some(pages) => {
+            let engine = store.engine();
+            let base = BaseTunables::for_target(engine.target());
+            Store::new_with_tunables(&**engine, LimitingTunables::new(base, Pages(pages)))
+        }
+        None => store.clone(),
+    }
+}
+
+/// Bytes of linear memory `instance_id` currently uses.
+pub fn memory_usage(instance_id: u64) -> Option<u64> {
+    let instance = GLOBAL_INSTANCE_MAP.get(&instance_id)?;
+    let memory = instance.exports.get_memory("memory").ok()?;
+    Some(memory.data_size())
+}
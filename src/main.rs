@@ -1,188 +1,102 @@
 #[macro_use]
 extern crate log;
 
-use std::ffi::{CStr, c_void};
-use std::os::raw::c_char;
-use std::borrow::{Borrow, BorrowMut};
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
+use calculator::{Arg, Response};
 use chashmap::CHashMap;
-use once_cell::sync::{Lazy, OnceCell};
-use serde::{Deserialize, Serialize};
-use wasmer::{
-    imports, Exports, Function, Global, Instance, LazyInit, Memory, MemoryView, Module, NativeFunc,
-    RuntimeError, Store, Val, WasmerEnv,
-};
-use we_logger::Record;
-use std::ops::Div;
+use log::LevelFilter;
+use once_cell::sync::Lazy;
+use wasmer::Instance;
+use we_codec::Format;
+use we_proto::record::Record;
+use structopt::StructOpt;
+use crate::backend::Backend;
+use crate::cache::ModuleCache;
+use crate::cli::Command;
+use crate::manager::{InstanceManager, InstanceStatus};
+use crate::manifest::{Entry, GatewaySpec, HttpSpec, KvSpec, Manifest, ModuleSpec, WasiSpec};
 use crate::scheduler::WasmFunctionExecution;
+use crate::service::GLOBAL_SERVICE_REGISTRY;
 
+mod abi;
+mod backend;
+mod cache;
+mod cli;
+mod env;
 mod error;
+mod gateway;
+mod http;
+mod imports;
+mod kv;
+mod lane;
+mod limits;
+mod manager;
+mod manifest;
+mod memory;
+mod metering;
+mod pool;
 mod scheduler;
+mod service;
+#[cfg(test)]
+mod testing;
+mod wasi;
+mod watch;
 
-static GLOBAL_INSTANCE_MAP: Lazy<CHashMap<u64, Instance>> = Lazy::new(|| CHashMap::new());
+static GLOBAL_INSTANCE_MAP: Lazy<CHashMap<u64, Instance>> = Lazy::new(CHashMap::new);
 
-#[derive(WasmerEnv, Clone)]
-struct Env {
-    name: OnceCell<Option<String>>,
-    instance_id: OnceCell<u64>,
-    #[wasmer(export(name = "NAME"))]
-    name_ptr: LazyInit<Global>,
-    #[wasmer(export)]
-    memory: LazyInit<Memory>,
-    #[wasmer(export)]
-    get_instance_id: LazyInit<NativeFunc<(), i64>>,
-    #[wasmer(export(name = "_wasm_malloc"))]
-    malloc: LazyInit<NativeFunc<i32, i32>>,
-    #[wasmer(export(name = "_wasm_free"))]
-    free: LazyInit<NativeFunc<(i32, i32)>>,
-    channel: tokio::sync::mpsc::UnboundedSender<(String, Vec<u8>)>,
-}
-
-impl Env {
-    fn new(channel: tokio::sync::mpsc::UnboundedSender<(String, Vec<u8>)>) -> Self {
-        Self {
-            name: Default::default(),
-            instance_id: Default::default(),
-            name_ptr: Default::default(),
-            memory: Default::default(),
-            get_instance_id: Default::default(),
-            malloc: Default::default(),
-            free: Default::default(),
-            channel,
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    pretty_env_logger::init();
+    let (mut manifest, watch, options) = match Command::from_args() {
+        Command::Run { modules, entry, module, format, wasi, dirs, allow_http, options } => {
+            let wasi = if wasi || !dirs.is_empty() {
+                Some(WasiSpec { preopen: dirs, ..Default::default() })
+            } else {
+                None
+            };
+            let modules = modules
+                .into_iter()
+                .map(|path| ModuleSpec {
+                    wasi: wasi.clone(),
+                    http: if allow_http.is_empty() { None } else { Some(HttpSpec::allow(allow_http.clone())) },
+                    ..ModuleSpec::from_path(path)
+                })
+                .collect();
+            let manifest = Manifest {
+                entry: Some(Entry { module, function: entry, timeout_ms: None, format, fuel: None }),
+                modules,
+                ..Default::default()
+            };
+            (manifest, false, options)
         }
+        Command::Deploy { manifest, watch, options } => (Manifest::from_file(manifest)?, watch, options),
+    };
+    if options.cache_dir.is_some() {
+        manifest.cache_dir = options.cache_dir;
     }
-
-    fn instance_id(&self) -> u64 {
-        *self.instance_id.get_or_init(|| {
-            self.get_instance_id
-                .get_ref()
-                .and_then(|f| f.call().map(|id| id as u64).ok())
-                .unwrap_or(0)
-        })
-    }
-
-    unsafe fn deref(&self, offset: usize) -> usize {
-        let memory = self.memory.get_unchecked();
-        let slice = memory.data_unchecked();
-        let mut buf = [0u8; 4];
-        buf.copy_from_slice(&slice[offset..offset + 4]);
-        std::mem::transmute::<[u8; 4], u32>(buf) as usize
-    }
-
-    unsafe fn get_str_unchecked(&self, offset: usize, len: usize) -> &str {
-        let memory = self.memory.get_unchecked();
-        let slice = memory.data_unchecked();
-        std::str::from_utf8_unchecked(&slice[offset..offset + len])
-    }
-
-    unsafe fn malloc(&self, size: usize) -> Result<usize, RuntimeError> {
-        let malloc = self.malloc.get_unchecked();
-        malloc.call(size as i32).map(|ret| ret as usize)
-    }
-
-    unsafe fn copy_from_slice(&self, addr: usize, slice: &[u8]) {
-        let memory = self.memory.get_unchecked();
-        let target = &mut memory.data_unchecked_mut()[addr..addr + slice.len()];
-        target.copy_from_slice(slice)
-    }
-
-    unsafe fn write_u32(&self, addr: usize, value: u32) {
-        let buf = std::mem::transmute::<u32, [u8; 4]>(value);
-        self.copy_from_slice(addr, &buf)
-    }
-
-    #[inline]
-    fn name(&self) -> Option<&str> {
-        self.name
-            .get_or_init(|| {
-                let offset = self.name_ptr.get_ref()?.get().i32()? as usize;
-                let memory = self.memory.get_ref()?;
-                unsafe {
-                    let name_ptr = memory.data_ptr().add(self.deref(offset)) as *const c_char;
-                    CStr::from_ptr(name_ptr).to_owned().into_string().ok()
-                }
-            })
-            .as_ref()
-            .map(|s| s.as_str())
-    }
-
-    #[inline]
-    fn get_string(&self, offset: usize, len: usize) -> String {
-        return String::from_utf8(self.get_bytes(offset, len)).unwrap();
+    if let Some(path) = options.kv_dir {
+        manifest.kv = KvSpec::Sled { path };
     }
-
-    #[inline]
-    fn get_bytes(&self, offset: usize, len: usize) -> Vec<u8> {
-        let view: MemoryView<u8> = self.memory.get_ref().unwrap().view();
-        (&view[offset..offset + len])
-            .iter()
-            .map(|u| u.get())
-            .collect()
-    }
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
-struct Response {
-    bar: i32,
-}
-
-fn callback(env: &Env, ptr: i32, len: i32, cb: i64, user_data: i64) {
-    use std::mem::{forget, transmute};
-
-    let mut rt = env.get_bytes(ptr as usize, len as usize);
-    let ptr = rt.as_mut_ptr();
-    let size = rt.len();
-    let cap = rt.capacity();
-    forget(rt);
-    unsafe {
-        let cb = cb as *mut c_void;
-        transmute::<_, unsafe extern "C" fn(*mut c_void, *const u8, usize, usize)>(cb)(user_data as *mut c_void, ptr, size, cap)
+    if let Some(listen) = options.listen {
+        match manifest.gateway {
+            Some(ref mut gateway) => gateway.listen = listen,
+            None => manifest.gateway = Some(GatewaySpec::listen(listen)),
+        }
     }
-}
-
-fn invoke(
-    env: &Env,
-    name_ptr: i32,
-    name_len: i32,
-    method_ptr: i32,
-    method_len: i32,
-    args_ptr: i32,
-    args_len: i32,
-    cb: i32,
-    user_data: i32,
-) {
-    let name = unsafe { env.get_str_unchecked(name_ptr as usize, name_len as usize) };
-    let method = env.get_string(method_ptr as usize, method_len as usize);
-    let args = env.get_string(args_ptr as usize, args_len as usize);
-
-    info!(
-        "request from <{}>#{}, {} {} {}",
-        env.name().unwrap_or("???"),
-        env.instance_id(),
-        name,
-        method,
-        args
+    let backend = Backend::default().with(
+        options.compiler.or(manifest.compiler),
+        options.engine.or(manifest.engine),
     );
-}
-
-fn log_proxy(env: &Env, record_ptr: i32, record_len: i32) {
-    let record_serialized = env.get_bytes(record_ptr as usize, record_len as usize);
-    let name = env.name().unwrap_or("???").to_string();
-    env.channel.send((name, record_serialized)).ok();
-}
-
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    pretty_env_logger::init();
 
     let (log_channel_tx, mut log_channel_rx) =
-        tokio::sync::mpsc::unbounded_channel::<(String, Vec<u8>)>();
+        tokio::sync::mpsc::unbounded_channel::<(String, LevelFilter, Format, Vec<u8>)>();
     tokio::spawn(async move {
-        while let Some((name, record_serialized)) = log_channel_rx.recv().await {
-            let record: bincode::Result<Record> = bincode::deserialize(&record_serialized);
+        while let Some((name, log_level, format, record_serialized)) = log_channel_rx.recv().await {
+            let record: we_codec::Result<Record> = format.decode(&record_serialized);
             match record {
-                Ok(record) => log!(
+                Ok(record) if record.level() <= log_level => log!(
                     target: &name,
                     record.level(),
                     "[{:<5}:{}]: {}",
@@ -192,54 +106,97 @@ async fn main() -> anyhow::Result<()> {
                         .map_or_else(|| "??".to_string(), |l| l.to_string()),
                     record.args()
                 ),
+                Ok(_) => {}
                 Err(e) => error!("cannot log module <{}>: {}", name, e),
             }
         }
     });
 
-    let store = Store::default();
-    let instance_id = AtomicU64::new(1);
+    GLOBAL_SERVICE_REGISTRY.register_native("host", "add_one", |arg: Arg| Response { bar: arg.foo + 1 });
 
-    let module = Module::from_file(&store, "target/wasm32-unknown-unknown/debug/hello.wasm")?;
+    let mut manager = InstanceManager::new(backend, log_channel_tx).with_kv(kv::open(&manifest.kv)?);
+    if let Some(ref cache_dir) = manifest.cache_dir {
+        manager = manager.with_cache(ModuleCache::new(cache_dir)?);
+    }
+    let manager = Arc::new(manager);
+    let instance_ids = manager.deploy(&manifest)?;
+    if let Some(ref gateway) = manifest.gateway {
+        gateway::serve(gateway, manager.clone())?;
+    }
 
-    let import_object = imports! {
-        "__wasm_everything_runtime__" => {
-            "invoke" => Function::new_native_with_env(
-                &store,
-                Env::new(log_channel_tx.clone()),
-                invoke
-            ),
-            "log_proxy" => Function::new_native_with_env(
-                &store,
-                Env::new(log_channel_tx.clone()),
-                log_proxy
-            ),
-            "callback" => Function::new_native_with_env(
-                &store,
-                Env::new(log_channel_tx.clone()),
-                callback
-            )
-        }
+    let _watcher = if watch {
+        let paths = manifest.modules.iter().map(|spec| spec.path.clone()).collect::<Vec<_>>();
+        let reloader = manager.clone();
+        tokio::spawn(async move {
+            let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+                Ok(hangup) => hangup,
+                Err(e) => return error!("cannot listen for SIGHUP: {}", e),
+            };
+            while hangup.recv().await.is_some() {
+                match reloader.reload_all() {
+                    Ok(instance_ids) => info!("reloaded all modules, new instances {:?}", instance_ids),
+                    Err(e) => error!("cannot reload modules: {}", e),
+                }
+            }
+        });
+        Some(watch::watch(manager.clone(), &paths)?)
+    } else {
+        None
     };
 
-    let mut instance = Instance::new(&module, &import_object)?;
-
-    // set instance id
-    let this_instance_id = instance_id.fetch_add(1, Ordering::SeqCst);
-    let set_instance_id = instance.exports.get_function("set_instance_id")?;
-    let set_instance_id_result = set_instance_id.call(&[Val::I64(this_instance_id as i64)])?;
-    if cfg!(debug_assertions) {
-        assert_eq!(set_instance_id_result[0].unwrap_i32(), 1);
-        let get_instance_id = instance.exports.get_function("get_instance_id")?;
-        assert_eq!(get_instance_id.call(&[])?[0].unwrap_i64(), this_instance_id as i64);
+    let entry = match manifest.entry {
+        Some(entry) => entry,
+        None => return shutdown(&manager).await,
+    };
+    let entry_instance_id = match entry.module {
+        Some(name) => GLOBAL_SERVICE_REGISTRY
+            .instance(&name)
+            .ok_or_else(|| anyhow::anyhow!("no module named <{}>", name))?,
+        None => *instance_ids
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("no module to call {}", entry.function))?,
+    };
+    // the execution owns what it calls, nothing of the manager is held while the call is pending
+    let mut execution = {
+        let instance = manager.instance(entry_instance_id)?;
+        let function = instance.exports.get_function(&entry.function)?;
+        WasmFunctionExecution::<()>::new(entry_instance_id, &instance, function).with_format(entry.format)
+    };
+    if let Some(timeout_ms) = entry.timeout_ms {
+        execution = execution.with_timeout(Duration::from_millis(timeout_ms));
+    }
+    if let Some(fuel) = entry.fuel {
+        execution = execution.with_fuel(fuel);
+    }
+    let response = execution.call_bytes().await?;
+    info!(
+        "{} consumed {} fuel, #{} consumed {} in total and uses {} bytes of memory",
+        entry.function,
+        execution.fuel_consumed(),
+        entry_instance_id,
+        metering::consumed(entry_instance_id),
+        limits::memory_usage(entry_instance_id).unwrap_or(0)
+    );
+    match entry.format {
+        Format::Json => println!("{}", String::from_utf8_lossy(&response)),
+        _ => println!("{:?}", response),
     }
-    let rt = GLOBAL_INSTANCE_MAP.insert(this_instance_id, instance);
-    debug_assert!(rt.is_none());
 
-    let instance = GLOBAL_INSTANCE_MAP.get(&this_instance_id).unwrap();
-    let hello = instance.exports.get_function("hello")?;
-    let response: Response = WasmFunctionExecution::new(hello).call().await?;
-    info!("{:?}", response);
+    // keep serving the gateway or reloading modules until ctrl-c
+    if manifest.gateway.is_some() || watch {
+        return shutdown(&manager).await;
+    }
+    Ok(())
+}
 
+/// Wait for ctrl-c, then terminate every running instance.
+async fn shutdown(manager: &InstanceManager) -> anyhow::Result<()> {
+    tokio::signal::ctrl_c().await?;
+    for instance in manager.instances() {
+        if instance.status == InstanceStatus::Running {
+            debug!("terminating <{}> #{}", instance.name, instance.id);
+            manager.terminate(instance.id)?;
+        }
+    }
     Ok(())
 }
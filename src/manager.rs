@@ -0,0 +1,484 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use chashmap::CHashMap;
+use tokio::runtime::Handle;
+use wasmer::{ChainableNamedResolver, Instance, Module, Val};
+
+use crate::abi;
+use crate::backend::Backend;
+use crate::cache::ModuleCache;
+use crate::env::{instance_name, Env, LogChannel};
+use crate::error::{encode_reply, Error};
+use crate::http::{self, HttpClient};
+use crate::imports::import_object;
+use crate::kv::{KvAccess, KvBackend};
+use crate::lane::LANES;
+use crate::limits;
+use crate::manifest::{Manifest, ModuleSpec};
+use crate::metering;
+use crate::scheduler::PENDING_CALLS;
+use crate::service::GLOBAL_SERVICE_REGISTRY;
+use crate::wasi;
+use crate::GLOBAL_INSTANCE_MAP;
+
+type Result<T> = std::result::Result<T, Error>;
+
+static INSTANCE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Terminated instances kept as records, calls to older ones fail with `Error::InstanceNotFound`.
+const TERMINATED_KEPT: usize = 128;
+
+/// How long replaced instances may take to finish their pending calls.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstanceStatus {
+    Running,
+    /// Replaced by a reload, finishing its pending calls without getting new ones
+    Draining,
+    Terminated,
+}
+
+#[derive(Debug, Clone)]
+pub struct InstanceInfo {
+    pub id: u64,
+    pub name: String,
+    pub status: InstanceStatus,
+}
+
+#[derive(Clone)]
+struct InstanceRecord {
+    name: String,
+    module: Module,
+    spec: Arc<ModuleSpec>,
+    status: InstanceStatus,
+    /// Calls the instance made which are still waiting for a reply
+    outstanding: Arc<AtomicUsize>,
+}
+
+/// Owns the lifecycle of guest instances.
+///
+/// Running instances live in `GLOBAL_INSTANCE_MAP` and are registered as services under their name,
+/// the last `TERMINATED_KEPT` terminated ones are only kept as records, so calls to them fail with `Error::Terminated`.
+pub struct InstanceManager {
+    /// Backend of modules not choosing one
+    backend: Backend,
+    log_channel: LogChannel,
+    runtime: Handle,
+    cache: Option<ModuleCache>,
+    kv: Option<Arc<dyn KvBackend>>,
+    /// Shared by the modules allowed outbound HTTP, created on first use
+    http: once_cell::sync::OnceCell<reqwest::Client>,
+    records: CHashMap<u64, InstanceRecord>,
+    /// Ids of the terminated records, oldest first
+    terminated: Mutex<VecDeque<u64>>,
+}
+
+impl InstanceManager {
+    /// A manager compiling modules with `backend` unless they choose another, must be created within a tokio runtime.
+    pub fn new(backend: Backend, log_channel: LogChannel) -> Self {
+        Self {
+            backend,
+            log_channel,
+            runtime: Handle::current(),
+            cache: None,
+            kv: None,
+            http: Default::default(),
+            records: CHashMap::new(),
+            terminated: Default::default(),
+        }
+    }
+
+    /// Load compiled modules from `cache` instead of compiling them every time.
+    pub fn with_cache(mut self, cache: ModuleCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Give every module a namespace in `backend` for `we_rt::kv`.
+    pub fn with_kv(mut self, backend: Arc<dyn KvBackend>) -> Self {
+        self.kv = Some(backend);
+        self
+    }
+
+    /// Compile and instantiate every module in the manifest, returns the instance ids in load order.
+    pub fn deploy(self: &Arc<Self>, manifest: &Manifest) -> anyhow::Result<Vec<u64>> {
+        let mut instance_ids = Vec::with_capacity(manifest.modules.len());
+        for spec in manifest.modules.iter() {
+            let spec = Arc::new(spec.clone());
+            let module = self.compile(&spec)?;
+            instance_ids.extend(self.spawn_pool(&module, spec)?);
+        }
+        Ok(instance_ids)
+    }
+
+    fn compile(&self, spec: &ModuleSpec) -> anyhow::Result<Module> {
+        let backend = self.backend.with(spec.compiler, spec.engine);
+        // the metering middleware of a store can only compile one module
+        let store = limits::store(&backend.store()?, &spec.limits);
+        let module = match self.cache {
+            Some(ref cache) => cache.load(&store, backend, &spec.path, &spec.limits)?,
+            None => Module::from_file(&store, &spec.path)?,
+        };
+        abi::check(&module, spec.wasi.is_some()).map_err(|e| anyhow::anyhow!("{}: {}", spec.path.display(), e))?;
+        Ok(module)
+    }
+
+    /// Instantiate `module`, register it under its service name and return its instance id.
+    pub fn spawn(&self, module: &Module, spec: Arc<ModuleSpec>) -> Result<u64> {
+        let this_instance_id = INSTANCE_ID.fetch_add(1, Ordering::SeqCst);
+        let outstanding = Arc::new(AtomicUsize::new(0));
+        let env = Env::new(
+            this_instance_id,
+            &spec,
+            self.log_channel.clone(),
+            outstanding.clone(),
+            self.runtime.clone(),
+            self.kv.clone().map(|backend| KvAccess::new(backend, spec.namespace())),
+            match spec.http {
+                Some(ref http) => Some(HttpClient::new(self.http.get_or_try_init(http::client)?.clone(), http)),
+                None => None,
+            },
+        );
+        let lane = env.lane().clone();
+        let import_object = import_object(module.store(), env);
+
+        let instance = match spec.wasi {
+            Some(ref wasi) if wasi::is_wasi_module(module) => {
+                let program = match spec.name {
+                    Some(ref name) => name.clone(),
+                    None => spec.path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default(),
+                };
+                let wasi_import_object = wasi::import_object(module, &program, wasi)?;
+                Instance::new(module, &wasi_import_object.chain_back(import_object))?
+            }
+            _ => Instance::new(module, &import_object)?,
+        };
+        // replies to the calls `init` makes are posted to the lane, they run once it returned
+        lane.enter(|| initialize(&instance, this_instance_id))?;
+
+        let name = match spec.name {
+            Some(ref name) => name.clone(),
+            None => instance_name(&instance).ok_or_else(|| Error::Abi("module has no valid NAME".to_string()))?,
+        };
+        info!(
+            "loaded <{}>#{}, {} bytes of memory",
+            name,
+            this_instance_id,
+            instance.exports.get_memory("memory")?.data_size()
+        );
+        let rt = GLOBAL_INSTANCE_MAP.insert(this_instance_id, instance);
+        debug_assert!(rt.is_none());
+        // nothing calls the instance before its lane is registered, a failed spawn leaves no limit behind
+        metering::set_limit(this_instance_id, spec.limits.fuel);
+        LANES.insert(this_instance_id, lane);
+        GLOBAL_SERVICE_REGISTRY.register_instance(name.clone(), this_instance_id);
+        self.records.insert(this_instance_id, InstanceRecord {
+            name,
+            module: module.clone(),
+            spec,
+            status: InstanceStatus::Running,
+            outstanding,
+        });
+
+        Ok(this_instance_id)
+    }
+
+    /// Spawn the initial instances of `spec`, and let its pool grow up to `max_instances` with `module`.
+    fn spawn_pool(self: &Arc<Self>, module: &Module, spec: Arc<ModuleSpec>) -> Result<Vec<u64>> {
+        let instance_ids = (0..spec.instances)
+            .map(|_| self.spawn(module, spec.clone()))
+            .collect::<Result<Vec<_>>>()?;
+
+        let max = spec.max_instances.unwrap_or(spec.instances);
+        let name = instance_ids.first().and_then(|id| self.records.get(id).map(|record| record.name.clone()));
+        if let (Some(name), true) = (name, max > instance_ids.len()) {
+            let (manager, module, spec) = (self.clone(), module.clone(), spec.clone());
+            GLOBAL_SERVICE_REGISTRY.register_pool(name, max, Arc::new(move || manager.spawn(&module, spec.clone())));
+        }
+        Ok(instance_ids)
+    }
+
+    /// Recompile the module at `path` and replace its running instances, returns the ids of the new instances.
+    ///
+    /// New calls are routed to the new instances right away, the old ones are terminated and forgotten
+    /// once they finished their pending calls, or after `DRAIN_TIMEOUT`.
+    pub fn reload(self: &Arc<Self>, path: &Path) -> anyhow::Result<Vec<u64>> {
+        let path = path.canonicalize()?;
+        let mut replaced: Vec<(Arc<ModuleSpec>, Vec<u64>)> = Vec::new();
+        for (id, record) in self.records.clone() {
+            if record.status != InstanceStatus::Running || record.spec.path.canonicalize().ok().as_ref() != Some(&path) {
+                continue;
+            }
+            match replaced.iter_mut().find(|(spec, _)| Arc::ptr_eq(spec, &record.spec)) {
+                Some((_, ids)) => ids.push(id),
+                None => replaced.push((record.spec, vec![id])),
+            }
+        }
+
+        let mut instance_ids = Vec::new();
+        for (spec, old_ids) in replaced {
+            let module = self.compile(&spec)?;
+            instance_ids.extend(self.spawn_pool(&module, spec)?);
+            for old_id in old_ids {
+                self.drain(old_id);
+            }
+        }
+        Ok(instance_ids)
+    }
+
+    /// Reload the modules of every running instance.
+    pub fn reload_all(self: &Arc<Self>) -> anyhow::Result<Vec<u64>> {
+        let mut paths = self
+            .records
+            .clone()
+            .into_iter()
+            .filter(|(_, record)| record.status == InstanceStatus::Running)
+            .map(|(_, record)| record.spec.path.clone())
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths.dedup();
+
+        let mut instance_ids = Vec::new();
+        for path in paths {
+            instance_ids.extend(self.reload(&path)?);
+        }
+        Ok(instance_ids)
+    }
+
+    /// Stop routing new calls to `instance_id`, terminate it once it is idle and drop its record.
+    fn drain(self: &Arc<Self>, instance_id: u64) {
+        let name = match self.records.get_mut(&instance_id) {
+            Some(mut record) => {
+                record.status = InstanceStatus::Draining;
+                record.name.clone()
+            }
+            None => return,
+        };
+        GLOBAL_SERVICE_REGISTRY.unregister_instance(&name, instance_id);
+
+        let manager = self.clone();
+        self.runtime.spawn(async move {
+            let deadline = Instant::now() + DRAIN_TIMEOUT;
+            while manager.is_busy(instance_id) && Instant::now() < deadline {
+                tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+            }
+            if let Err(e) = manager.terminate(instance_id) {
+                warn!("cannot terminate drained <{}>#{}: {}", name, instance_id, e);
+            }
+            // replaced by a new instance, the record would only keep the old module alive
+            manager.forget(instance_id);
+        });
+    }
+
+    /// Drop the record of `instance_id`, calls to it fail with `Error::InstanceNotFound`.
+    fn forget(&self, instance_id: u64) {
+        self.terminated.lock().unwrap().retain(|id| *id != instance_id);
+        self.records.remove(&instance_id);
+    }
+
+    /// Whether calls into or out of `instance_id` are still waiting for a reply.
+    fn is_busy(&self, instance_id: u64) -> bool {
+        let outstanding = self
+            .records
+            .get(&instance_id)
+            .map_or(0, |record| record.outstanding.load(Ordering::SeqCst));
+        outstanding > 0 || PENDING_CALLS.pending(instance_id) > 0
+    }
+
+    /// Stop routing calls to `instance_id` and drop it, calls still waiting for it fail with `Error::Terminated`.
+    pub fn terminate(&self, instance_id: u64) -> Result<()> {
+        let name = {
+            let mut record = self.records.get_mut(&instance_id).ok_or(Error::InstanceNotFound(instance_id))?;
+            if record.status == InstanceStatus::Terminated {
+                return Err(Error::Terminated(instance_id));
+            }
+            record.status = InstanceStatus::Terminated;
+            record.name.clone()
+        };
+
+        GLOBAL_SERVICE_REGISTRY.unregister_instance(&name, instance_id);
+        GLOBAL_INSTANCE_MAP.remove(&instance_id);
+        LANES.remove(&instance_id);
+        metering::forget(instance_id);
+        let reply = encode_reply(&Err(Error::Terminated(instance_id)))?;
+        let failed = PENDING_CALLS.resolve_owner(instance_id, &reply);
+        info!("terminated <{}>#{}, {} pending calls failed", name, instance_id, failed);
+
+        let mut terminated = self.terminated.lock().unwrap();
+        terminated.push_back(instance_id);
+        while terminated.len() > TERMINATED_KEPT {
+            if let Some(oldest) = terminated.pop_front() {
+                self.records.remove(&oldest);
+            }
+        }
+        Ok(())
+    }
+
+    /// Terminate `instance_id` if it is running and spawn a fresh instance of its module, returns the new id.
+    #[allow(dead_code)]
+    pub fn restart(&self, instance_id: u64) -> Result<u64> {
+        let (module, spec) = {
+            let record = self.records.get(&instance_id).ok_or(Error::InstanceNotFound(instance_id))?;
+            (record.module.clone(), record.spec.clone())
+        };
+        match self.terminate(instance_id) {
+            Ok(()) | Err(Error::Terminated(_)) => {}
+            Err(e) => return Err(e),
+        }
+        self.spawn(&module, spec)
+    }
+
+    pub fn status(&self, instance_id: u64) -> Option<InstanceStatus> {
+        self.records.get(&instance_id).map(|record| record.status)
+    }
+
+    /// Instances running, draining or recently terminated, in no particular order.
+    pub fn instances(&self) -> Vec<InstanceInfo> {
+        self.records
+            .clone()
+            .into_iter()
+            .map(|(id, record)| InstanceInfo { id, name: record.name, status: record.status })
+            .collect()
+    }
+
+    /// A running instance, calls to draining or terminated ones are refused.
+    ///
+    /// The instance is cloned out of `GLOBAL_INSTANCE_MAP`, holding it does not keep the map locked.
+    pub fn instance(&self, instance_id: u64) -> Result<Instance> {
+        match self.status(instance_id) {
+            None => Err(Error::InstanceNotFound(instance_id)),
+            Some(InstanceStatus::Draining) | Some(InstanceStatus::Terminated) => Err(Error::Terminated(instance_id)),
+            Some(InstanceStatus::Running) => GLOBAL_INSTANCE_MAP
+                .get(&instance_id)
+                .map(|instance| instance.clone())
+                .ok_or(Error::Terminated(instance_id)),
+        }
+    }
+}
+
+/// Initialize a new instance and hand it its id, then run its `init`.
+fn initialize(instance: &Instance, instance_id: u64) -> Result<()> {
+    // WASI reactors initialize their libc before any other export is called
+    if let Ok(initialize) = instance.exports.get_native_function::<(), ()>("_initialize") {
+        initialize.call()?;
+    }
+
+    let set_instance_id = instance.exports.get_function("set_instance_id")?;
+    let set_instance_id_result = set_instance_id.call(&[Val::I64(instance_id as i64)])?;
+    if cfg!(debug_assertions) {
+        assert_eq!(set_instance_id_result[0].unwrap_i32(), 1);
+        let get_instance_id = instance.exports.get_function("get_instance_id")?;
+        assert_eq!(get_instance_id.call(&[])?[0].unwrap_i64(), instance_id as i64);
+    }
+    if let Ok(init) = instance.exports.get_native_function::<(), ()>("init") {
+        init.call()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::WasmFunctionExecution;
+    use crate::testing::{guest, manager, spec, ECHO};
+
+    #[tokio::test]
+    async fn reloads_forget_replaced_instances() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager();
+        let spec = spec(dir.path(), "reloaded", &guest(0, ""));
+        let path = spec.path.clone();
+        let old_id = manager.deploy(&Manifest { modules: vec![spec], ..Default::default() }).unwrap()[0];
+
+        // the second reload replaces the instance of the first one
+        manager.reload(&path).unwrap();
+        let new_id = manager.reload(&path).unwrap()[0];
+        tokio::time::sleep(DRAIN_POLL_INTERVAL * 3).await;
+
+        let instances = manager.instances();
+        assert_eq!(instances.len(), 1);
+        assert_eq!((instances[0].id, instances[0].status), (new_id, InstanceStatus::Running));
+        assert!(matches!(manager.instance(old_id), Err(Error::InstanceNotFound(_))));
+        assert!(manager.terminated.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reloads_do_not_wait_for_callers_holding_the_instance() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager();
+        let spec = spec(dir.path(), "held", &guest(0, ECHO));
+        let path = spec.path.clone();
+        let old_id = manager.deploy(&Manifest { modules: vec![spec], ..Default::default() }).unwrap()[0];
+        let held = manager.instance(old_id).unwrap();
+
+        let new_id = manager.reload(&path).unwrap()[0];
+        let forgotten = async {
+            while manager.status(old_id).is_some() {
+                tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), forgotten).await.expect("the old instance was never terminated");
+        assert_eq!(manager.status(new_id), Some(InstanceStatus::Running));
+
+        // the held instance outlives its termination, calls to it are refused
+        let echo = held.exports.get_function("echo").unwrap();
+        let reply = WasmFunctionExecution::<()>::new(old_id, &held, echo).call_bytes_with(b"late").await;
+        assert!(matches!(reply, Err(Error::Terminated(id)) if id == old_id), "{:?}", reply);
+    }
+
+    #[tokio::test]
+    async fn restarts_spawn_a_fresh_instance() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager();
+        let spec = spec(dir.path(), "restarted", &guest(0, ""));
+        let old_id = manager.deploy(&Manifest { modules: vec![spec], ..Default::default() }).unwrap()[0];
+
+        let new_id = manager.restart(old_id).unwrap();
+        assert_ne!(new_id, old_id);
+        assert_eq!(manager.status(old_id), Some(InstanceStatus::Terminated));
+        assert_eq!(manager.status(new_id), Some(InstanceStatus::Running));
+        assert_eq!(GLOBAL_SERVICE_REGISTRY.instance("restarted"), Some(new_id));
+
+        // terminated instances can be restarted too
+        manager.terminate(new_id).unwrap();
+        let restarted_id = manager.restart(new_id).unwrap();
+        assert_eq!(manager.status(restarted_id), Some(InstanceStatus::Running));
+        assert!(matches!(manager.restart(u64::MAX), Err(Error::InstanceNotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn only_the_last_terminated_instances_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager();
+        let mut spec = spec(dir.path(), "many", &guest(0, ""));
+        spec.instances = TERMINATED_KEPT + 2;
+        let instance_ids = manager.deploy(&Manifest { modules: vec![spec], ..Default::default() }).unwrap();
+        for instance_id in instance_ids.iter() {
+            manager.terminate(*instance_id).unwrap();
+        }
+
+        assert_eq!(manager.instances().len(), TERMINATED_KEPT);
+        assert!(matches!(manager.instance(instance_ids[0]), Err(Error::InstanceNotFound(_))));
+        assert!(matches!(manager.terminate(instance_ids[1]), Err(Error::InstanceNotFound(_))));
+        assert!(matches!(manager.instance(instance_ids[2]), Err(Error::Terminated(_))));
+    }
+
+    #[tokio::test]
+    async fn every_module_gets_its_own_metering() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager();
+        let mut first = spec(dir.path(), "first", &guest(0, ""));
+        first.limits.fuel = Some(1_000_000);
+        let second = spec(dir.path(), "second", &guest(0, r#"(func (export "init"))"#));
+        for spec in [&first, &second, &first].iter() {
+            let module = manager.compile(spec).unwrap();
+            assert!(module.exports().any(|export| export.name() == metering::REMAINING_POINTS));
+        }
+    }
+}
@@ -0,0 +1,433 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use log::LevelFilter;
+use serde::Deserialize;
+
+use we_codec::Format;
+
+use crate::backend::{Compiler, Engine};
+
+/// Deployment manifest, describes which modules a host loads and how.
+///
+/// ```toml
+/// cache_dir = "target/module-cache"
+/// compiler = "cranelift"
+/// engine = "jit"
+///
+/// [kv]
+/// backend = "sled"
+/// path = "data/kv"
+///
+/// [gateway]
+/// listen = "127.0.0.1:8080"
+/// timeout_ms = 30000
+/// fuel = 10000000
+///
+/// [entry]
+/// module = "hello"
+/// function = "hello"
+/// timeout_ms = 5000
+/// format = "json"
+/// fuel = 5000000
+///
+/// [[module]]
+/// path = "target/wasm32-unknown-unknown/debug/hello.wasm"
+/// instances = 2
+/// max_instances = 8
+/// log_level = "info"
+/// compiler = "llvm"
+/// kv_namespace = "greeter"
+///
+/// [module.config]
+/// greeting = "hi"
+///
+/// [module.limits]
+/// memory_pages = 32
+/// fuel = 1000000
+///
+/// [module.http]
+/// allow = ["api.example.com", "*.internal.example.com", "127.0.0.1:8080"]
+/// max_body_bytes = 1048576
+///
+/// [module.wasi]
+/// args = ["--verbose"]
+/// env = { LANG = "C" }
+/// preopen = ["data"]
+/// map_dirs = { "/tmp" = "scratch" }
+/// ```
+#[derive(Deserialize, Debug, Default)]
+pub struct Manifest {
+    #[serde(default)]
+    pub entry: Option<Entry>,
+    #[serde(rename = "module", alias = "modules", default)]
+    pub modules: Vec<ModuleSpec>,
+    /// Directory to cache compiled modules in, modules are compiled on every start without it
+    #[serde(default)]
+    pub cache_dir: Option<PathBuf>,
+    /// Compiler for modules not choosing one, defaults to cranelift
+    #[serde(default)]
+    pub compiler: Option<Compiler>,
+    /// Engine for modules not choosing one, defaults to jit
+    #[serde(default)]
+    pub engine: Option<Engine>,
+    /// Backend of the key-value store guests use through `we_rt::kv`
+    #[serde(default)]
+    pub kv: KvSpec,
+    /// HTTP server exposing the exports of the modules, see `gateway`
+    #[serde(default)]
+    pub gateway: Option<GatewaySpec>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Entry {
+    /// Service name of the module exporting `function`, defaults to the first module
+    #[serde(default)]
+    pub module: Option<String>,
+    pub function: String,
+    /// Milliseconds to wait for `function` to reply
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Format `function` replies in, `json` prints the reply as text
+    #[serde(default)]
+    pub format: Format,
+    /// Fuel the call may consume instead of the `fuel` limit of the module, calls it makes are granted the same
+    #[serde(default)]
+    pub fuel: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ModuleSpec {
+    pub path: PathBuf,
+    /// Service name to register instances under, defaults to the `NAME` the module exports
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Instances spawned at startup
+    #[serde(default = "default_instances")]
+    pub instances: usize,
+    /// Upper bound of the instance pool, more instances are spawned on demand while all are busy,
+    /// past it calls wait for an instance. Defaults to `instances`
+    #[serde(default)]
+    pub max_instances: Option<usize>,
+    /// Values the guest can read through `we_rt::config`
+    #[serde(default)]
+    pub config: HashMap<String, String>,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default = "default_log_level")]
+    pub log_level: LevelFilter,
+    #[serde(default)]
+    pub compiler: Option<Compiler>,
+    #[serde(default)]
+    pub engine: Option<Engine>,
+    /// WASI capabilities, modules importing WASI are refused without it
+    #[serde(default)]
+    pub wasi: Option<WasiSpec>,
+    /// Outbound HTTP the module may do, it can make no requests without it
+    #[serde(default)]
+    pub http: Option<HttpSpec>,
+    /// Key-value namespace of the module, defaults to its `name` or else to a name derived from its path.
+    /// Modules sharing a namespace share their keys
+    #[serde(default)]
+    pub kv_namespace: Option<String>,
+}
+
+/// Capabilities a WASI module is granted, it gets nothing else from the host system.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct WasiSpec {
+    /// Arguments after the program name
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Environment variables
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Host directories the guest can access at the same path
+    #[serde(default)]
+    pub preopen: Vec<PathBuf>,
+    /// Host directories the guest can access, by guest path
+    #[serde(default)]
+    pub map_dirs: HashMap<String, PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HttpSpec {
+    /// Hosts the module may call: `example.com` on any port, `example.com:8080` on that port only,
+    /// `*.example.com` for its subdomains and `[::1]:8080` for IPv6 addresses
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Largest response body accepted
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+    /// Milliseconds before a request times out
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+impl HttpSpec {
+    pub fn allow(allow: Vec<String>) -> Self {
+        Self { allow, max_body_bytes: default_max_body_bytes(), timeout_ms: None }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum KvSpec {
+    /// Keys are lost when the host exits
+    #[default]
+    Memory,
+    /// Keys are stored in a sled database at `path`
+    Sled { path: PathBuf },
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GatewaySpec {
+    /// Address to serve `POST /{module}/{function}` on
+    pub listen: SocketAddr,
+    /// Largest request body accepted
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+    /// Milliseconds to wait for a guest to reply before answering `504 Gateway Timeout`
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Fuel every call may consume instead of the `fuel` limit of its module, calls it makes are granted the same
+    #[serde(default)]
+    pub fuel: Option<u64>,
+}
+
+impl GatewaySpec {
+    pub fn listen(listen: SocketAddr) -> Self {
+        Self { listen, max_body_bytes: default_max_body_bytes(), timeout_ms: None, fuel: None }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct Limits {
+    /// Maximum number of 64KiB linear memory pages per instance, growing past it fails in the guest
+    #[serde(default)]
+    pub memory_pages: Option<u32>,
+    /// Fuel, roughly wasm operators executed, every call into an instance may consume
+    #[serde(default)]
+    pub fuel: Option<u64>,
+}
+
+fn default_instances() -> usize {
+    1
+}
+
+fn default_log_level() -> LevelFilter {
+    LevelFilter::Trace
+}
+
+fn default_max_body_bytes() -> usize {
+    4 << 20
+}
+
+impl Manifest {
+    /// Load a manifest from a `.toml`, `.yaml` or `.yml` file.
+    ///
+    /// Relative module paths are resolved against the directory of the manifest.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+        let mut manifest: Manifest = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&content)?,
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&content)?,
+            _ => anyhow::bail!("unknown manifest format: {}", path.display()),
+        };
+
+        if let Some(base) = path.parent() {
+            for module in manifest.modules.iter_mut() {
+                if module.path.is_relative() {
+                    module.path = base.join(&module.path);
+                }
+                if let Some(ref mut wasi) = module.wasi {
+                    let dirs = wasi.preopen.iter_mut().chain(wasi.map_dirs.values_mut());
+                    for dir in dirs.filter(|dir| dir.is_relative()) {
+                        *dir = base.join(&dir);
+                    }
+                }
+            }
+            if let Some(ref mut cache_dir) = manifest.cache_dir {
+                if cache_dir.is_relative() {
+                    *cache_dir = base.join(&cache_dir);
+                }
+            }
+            if let KvSpec::Sled { ref mut path } = manifest.kv {
+                if path.is_relative() {
+                    *path = base.join(&path);
+                }
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+impl ModuleSpec {
+    pub fn from_path(path: PathBuf) -> Self {
+        Self {
+            path,
+            name: None,
+            instances: default_instances(),
+            max_instances: None,
+            config: Default::default(),
+            limits: Default::default(),
+            log_level: default_log_level(),
+            compiler: None,
+            engine: None,
+            wasi: None,
+            http: None,
+            kv_namespace: None,
+        }
+    }
+
+    /// Key-value namespace of the module, assigned by the host and never by the guest:
+    /// `kv_namespace`, else `name`, else a hash of the path.
+    pub fn namespace(&self) -> String {
+        if let Some(namespace) = self.kv_namespace.as_ref().or(self.name.as_ref()) {
+            return namespace.clone();
+        }
+        let path = self.path.canonicalize().unwrap_or_else(|_| self.path.clone());
+        format!("module-{}", blake3::hash(path.to_string_lossy().as_bytes()).to_hex())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// Write `content` to `name` in a fresh directory and load it.
+    fn load(name: &str, content: &str) -> (TempDir, anyhow::Result<Manifest>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(name);
+        std::fs::write(&path, content).unwrap();
+        let manifest = Manifest::from_file(&path);
+        (dir, manifest)
+    }
+
+    #[test]
+    fn toml_manifests() {
+        let (dir, manifest) = load(
+            "deploy.toml",
+            r#"
+            cache_dir = "cache"
+            compiler = "cranelift"
+
+            [kv]
+            backend = "sled"
+            path = "data/kv"
+
+            [gateway]
+            listen = "127.0.0.1:8080"
+            fuel = 2000
+
+            [entry]
+            function = "hello"
+            format = "json"
+            fuel = 500
+
+            [[module]]
+            path = "hello.wasm"
+            instances = 2
+            max_instances = 8
+            log_level = "info"
+
+            [module.config]
+            greeting = "hi"
+
+            [module.limits]
+            fuel = 1000
+
+            [module.wasi]
+            preopen = ["data", "/srv/shared"]
+            map_dirs = { "/tmp" = "scratch" }
+
+            [[module]]
+            path = "/srv/modules/other.wasm"
+            "#,
+        );
+        let manifest = manifest.unwrap();
+        let base = dir.path();
+
+        let entry = manifest.entry.unwrap();
+        assert_eq!((entry.module, entry.function.as_str(), entry.format), (None, "hello", Format::Json));
+        assert_eq!(entry.fuel, Some(500));
+        assert_eq!(manifest.compiler, Some(Compiler::Cranelift));
+        assert_eq!(manifest.engine, None);
+        assert_eq!(manifest.cache_dir, Some(base.join("cache")));
+        assert!(matches!(manifest.kv, KvSpec::Sled { ref path } if *path == base.join("data/kv")));
+        let gateway = manifest.gateway.unwrap();
+        assert_eq!(gateway.listen, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!((gateway.max_body_bytes, gateway.fuel), (default_max_body_bytes(), Some(2000)));
+
+        let [hello, other] = match &manifest.modules[..] {
+            [hello, other] => [hello, other],
+            modules => panic!("{:?}", modules),
+        };
+        assert_eq!(hello.path, base.join("hello.wasm"));
+        assert_eq!((hello.instances, hello.max_instances), (2, Some(8)));
+        assert_eq!(hello.log_level, LevelFilter::Info);
+        assert_eq!(hello.config["greeting"], "hi");
+        assert_eq!((hello.limits.fuel, hello.limits.memory_pages), (Some(1000), None));
+        let wasi = hello.wasi.as_ref().unwrap();
+        assert_eq!(wasi.preopen, [base.join("data"), PathBuf::from("/srv/shared")]);
+        assert_eq!(wasi.map_dirs["/tmp"], base.join("scratch"));
+
+        // absolute paths and defaults
+        assert_eq!(other.path, PathBuf::from("/srv/modules/other.wasm"));
+        assert_eq!((other.instances, other.max_instances), (default_instances(), None));
+        assert_eq!(other.log_level, default_log_level());
+        assert!(other.wasi.is_none() && other.http.is_none() && other.kv_namespace.is_none());
+    }
+
+    #[test]
+    fn yaml_manifests() {
+        for name in ["deploy.yaml", "deploy.yml"] {
+            let (dir, manifest) = load(
+                name,
+                r#"
+                modules:
+                  - path: hello.wasm
+                    name: greeter
+                    http:
+                      allow: ["api.example.com"]
+                "#,
+            );
+            let manifest = manifest.unwrap();
+            let hello = &manifest.modules[0];
+            assert_eq!(hello.path, dir.path().join("hello.wasm"));
+            assert_eq!(hello.name.as_deref(), Some("greeter"));
+            let http = hello.http.as_ref().unwrap();
+            assert_eq!(http.allow, ["api.example.com"]);
+            assert_eq!(http.max_body_bytes, default_max_body_bytes());
+            assert!(matches!(manifest.kv, KvSpec::Memory));
+            assert!(manifest.entry.is_none() && manifest.gateway.is_none() && manifest.cache_dir.is_none());
+        }
+    }
+
+    #[test]
+    fn invalid_manifests_are_refused() {
+        assert!(load("deploy.json", "{}").1.is_err());
+        assert!(load("deploy", "").1.is_err());
+        // a module needs a path
+        assert!(load("deploy.toml", "[[module]]\ninstances = 2").1.is_err());
+        assert!(load("deploy.toml", "[kv]\nbackend = \"redis\"").1.is_err());
+        assert!(Manifest::from_file("/nonexistent/deploy.toml").is_err());
+    }
+
+    #[test]
+    fn namespaces_are_assigned_by_the_host() {
+        let mut spec = ModuleSpec::from_path(PathBuf::from("/srv/modules/hello.wasm"));
+        let by_path = spec.namespace();
+        assert!(by_path.starts_with("module-"), "{}", by_path);
+        assert_eq!(by_path, ModuleSpec::from_path(PathBuf::from("/srv/modules/hello.wasm")).namespace());
+        assert_ne!(by_path, ModuleSpec::from_path(PathBuf::from("/srv/modules/other.wasm")).namespace());
+
+        spec.name = Some("hello".to_string());
+        assert_eq!(spec.namespace(), "hello");
+        spec.kv_namespace = Some("greeter".to_string());
+        assert_eq!(spec.namespace(), "greeter");
+    }
+}
@@ -0,0 +1,248 @@
+use std::cell::Cell;
+use std::marker::PhantomData;
+
+use wasmer::Memory;
+
+use crate::error::Error;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// A value stored in guest memory, wasm is little endian.
+pub trait GuestValue: Sized {
+    const SIZE: usize;
+
+    fn from_le_slice(bytes: &[u8]) -> Self;
+
+    fn write_le_slice(&self, bytes: &mut [u8]);
+}
+
+macro_rules! impl_guest_value {
+    ($($ty:ty),*) => {
+        $(
+            impl GuestValue for $ty {
+                const SIZE: usize = std::mem::size_of::<$ty>();
+
+                #[inline]
+                fn from_le_slice(bytes: &[u8]) -> Self {
+                    let mut buf = [0u8; std::mem::size_of::<$ty>()];
+                    buf.copy_from_slice(bytes);
+                    <$ty>::from_le_bytes(buf)
+                }
+
+                #[inline]
+                fn write_le_slice(&self, bytes: &mut [u8]) {
+                    bytes.copy_from_slice(&self.to_le_bytes())
+                }
+            }
+        )*
+    };
+}
+
+impl_guest_value!(u8, i8, u16, i16, u32, i32, u64, i64);
+
+/// An offset into guest memory pointing at a `T`.
+#[derive(Debug)]
+pub struct GuestPtr<T> {
+    offset: u32,
+    _type: PhantomData<T>,
+}
+
+impl<T> Clone for GuestPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for GuestPtr<T> {}
+
+impl<T> GuestPtr<T> {
+    #[inline]
+    pub fn new(offset: u32) -> Self {
+        Self { offset, _type: PhantomData }
+    }
+
+    #[inline]
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+}
+
+/// `len` consecutive `T`s in guest memory.
+#[derive(Debug)]
+pub struct GuestSlice<T> {
+    ptr: GuestPtr<T>,
+    len: u32,
+}
+
+impl<T> Clone for GuestSlice<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for GuestSlice<T> {}
+
+impl<T> GuestSlice<T> {
+    #[inline]
+    pub fn new(offset: u32, len: u32) -> Self {
+        Self { ptr: GuestPtr::new(offset), len }
+    }
+
+    #[inline]
+    pub fn ptr(&self) -> GuestPtr<T> {
+        self.ptr
+    }
+
+    #[inline]
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+}
+
+/// Bounds checked access to the linear memory of a guest.
+///
+/// Every access validates the range against the current memory size, so a guest growing
+/// its memory in between accesses is fine, violations are reported as `Error::OutOfBounds`.
+pub struct GuestMemory<'a> {
+    memory: &'a Memory,
+}
+
+impl<'a> GuestMemory<'a> {
+    pub fn new(memory: &'a Memory) -> Self {
+        Self { memory }
+    }
+
+    fn with_cells<R, F>(&self, offset: u32, len: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&[Cell<u8>]) -> R,
+    {
+        let view = self.memory.view::<u8>();
+        let start = offset as usize;
+        let cells = start
+            .checked_add(len)
+            .and_then(|end| view.get(start..end))
+            .ok_or(Error::OutOfBounds { offset, len })?;
+        Ok(f(cells))
+    }
+
+    pub fn read<T: GuestValue>(&self, ptr: GuestPtr<T>) -> Result<T> {
+        self.with_cells(ptr.offset, T::SIZE, |cells| {
+            let bytes = cells.iter().map(Cell::get).collect::<Vec<_>>();
+            T::from_le_slice(&bytes)
+        })
+    }
+
+    pub fn write<T: GuestValue>(&self, ptr: GuestPtr<T>, value: T) -> Result<()> {
+        let mut bytes = vec![0u8; T::SIZE];
+        value.write_le_slice(&mut bytes);
+        self.write_bytes(GuestPtr::new(ptr.offset), &bytes)
+    }
+
+    pub fn read_slice<T: GuestValue>(&self, slice: GuestSlice<T>) -> Result<Vec<T>> {
+        let len = (slice.len as usize)
+            .checked_mul(T::SIZE)
+            .ok_or(Error::OutOfBounds { offset: slice.ptr.offset, len: usize::MAX })?;
+        self.with_cells(slice.ptr.offset, len, |cells| {
+            let bytes = cells.iter().map(Cell::get).collect::<Vec<_>>();
+            bytes.chunks_exact(T::SIZE).map(T::from_le_slice).collect()
+        })
+    }
+
+    pub fn read_bytes(&self, slice: GuestSlice<u8>) -> Result<Vec<u8>> {
+        self.read_slice(slice)
+    }
+
+    pub fn write_bytes(&self, ptr: GuestPtr<u8>, data: &[u8]) -> Result<()> {
+        self.with_cells(ptr.offset, data.len(), |cells| {
+            for (cell, byte) in cells.iter().zip(data) {
+                cell.set(*byte);
+            }
+        })
+    }
+
+    /// Read a UTF-8 string, invalid UTF-8 is an error.
+    pub fn read_str(&self, slice: GuestSlice<u8>) -> Result<String> {
+        Ok(String::from_utf8(self.read_bytes(slice)?)?)
+    }
+
+    /// Read a NUL terminated UTF-8 string.
+    pub fn read_c_str(&self, ptr: GuestPtr<u8>) -> Result<String> {
+        let view = self.memory.view::<u8>();
+        let start = ptr.offset as usize;
+        let tail = view.get(start..).ok_or(Error::OutOfBounds { offset: ptr.offset, len: 1 })?;
+        let len = tail
+            .iter()
+            .position(|cell| cell.get() == 0)
+            .ok_or(Error::OutOfBounds { offset: ptr.offset, len: tail.len() + 1 })?;
+        self.read_str(GuestSlice::new(ptr.offset, len as u32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmer::{MemoryType, Pages, Store};
+
+    use super::*;
+
+    const PAGE: u32 = 1 << 16;
+
+    fn memory() -> Memory {
+        Memory::new(&Store::default(), MemoryType::new(1, None, false)).unwrap()
+    }
+
+    fn out_of_bounds<T: std::fmt::Debug>(result: Result<T>) -> (u32, usize) {
+        match result {
+            Err(Error::OutOfBounds { offset, len }) => (offset, len),
+            result => panic!("expected out of bounds, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn values_round_trip_up_to_the_end() {
+        let memory = memory();
+        let memory = GuestMemory::new(&memory);
+        memory.write(GuestPtr::<u32>::new(PAGE - 4), 0xdead_beef).unwrap();
+        assert_eq!(memory.read(GuestPtr::<u32>::new(PAGE - 4)).unwrap(), 0xdead_beef);
+        assert_eq!(memory.read_bytes(GuestSlice::new(PAGE - 4, 4)).unwrap(), [0xef, 0xbe, 0xad, 0xde]);
+        memory.write(GuestPtr::<i64>::new(8), -2).unwrap();
+        assert_eq!(memory.read_slice(GuestSlice::<i64>::new(8, 1)).unwrap(), [-2]);
+    }
+
+    #[test]
+    fn accesses_past_the_end_are_refused() {
+        let memory = memory();
+        let memory = GuestMemory::new(&memory);
+        assert_eq!(out_of_bounds(memory.read(GuestPtr::<u32>::new(PAGE - 3))), (PAGE - 3, 4));
+        assert_eq!(out_of_bounds(memory.write(GuestPtr::<u8>::new(PAGE), 1)), (PAGE, 1));
+        assert_eq!(out_of_bounds(memory.write_bytes(GuestPtr::new(PAGE - 1), &[1, 2])), (PAGE - 1, 2));
+        assert_eq!(out_of_bounds(memory.read_bytes(GuestSlice::new(u32::MAX, 2))), (u32::MAX, 2));
+        assert_eq!(out_of_bounds(memory.read_slice(GuestSlice::<u64>::new(0, u32::MAX))).0, 0);
+        // nothing was written by the refused accesses
+        assert_eq!(memory.read_bytes(GuestSlice::new(PAGE - 1, 1)).unwrap(), [0]);
+    }
+
+    #[test]
+    fn grown_memory_is_accessible() {
+        let memory = memory();
+        assert!(GuestMemory::new(&memory).read(GuestPtr::<u8>::new(PAGE)).is_err());
+        memory.grow(Pages(1)).unwrap();
+        assert_eq!(GuestMemory::new(&memory).read(GuestPtr::<u8>::new(PAGE)).unwrap(), 0);
+    }
+
+    #[test]
+    fn strings() {
+        let memory = memory();
+        let memory = GuestMemory::new(&memory);
+        memory.write_bytes(GuestPtr::new(16), b"hello\0").unwrap();
+        assert_eq!(memory.read_c_str(GuestPtr::new(16)).unwrap(), "hello");
+        assert_eq!(memory.read_str(GuestSlice::new(16, 4)).unwrap(), "hell");
+
+        memory.write_bytes(GuestPtr::new(32), &[0xff, 0]).unwrap();
+        assert!(matches!(memory.read_c_str(GuestPtr::new(32)), Err(Error::Utf8(_))));
+
+        // no terminator before the end of memory
+        memory.write_bytes(GuestPtr::new(PAGE - 2), b"ab").unwrap();
+        assert_eq!(out_of_bounds(memory.read_c_str(GuestPtr::new(PAGE - 2))), (PAGE - 2, 3));
+        assert_eq!(out_of_bounds(memory.read_c_str(GuestPtr::new(PAGE + 1))), (PAGE + 1, 1));
+    }
+}
@@ -0,0 +1,229 @@
+use std::sync::Arc;
+
+use chashmap::CHashMap;
+use once_cell::sync::Lazy;
+use wasmer::wasmparser::Operator;
+use wasmer::{Global, Instance, RuntimeError, Val};
+use wasmer_middlewares::metering::MeteringPoints;
+use wasmer_middlewares::Metering;
+
+use crate::error::Error;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Globals the metering middleware adds to every module, see `wasmer_middlewares::metering::get_remaining_points`.
+pub const REMAINING_POINTS: &str = "wasmer_metering_remaining_points";
+pub const POINTS_EXHAUSTED: &str = "wasmer_metering_points_exhausted";
+
+/// Fuel granted to every call into an instance, by instance id. Instances without an entry are unlimited.
+static LIMITS: Lazy<CHashMap<u64, u64>> = Lazy::new(CHashMap::new);
+
+/// Fuel consumed by every instance so far, by instance id.
+static CONSUMED: Lazy<CHashMap<u64, u64>> = Lazy::new(CHashMap::new);
+
+/// Fuel of the call running in an instance when it was given its own, by instance id. See `call_fuel`.
+static CALL_FUEL: Lazy<CHashMap<u64, u64>> = Lazy::new(CHashMap::new);
+
+/// Points an operator costs.
+type Cost = fn(&Operator) -> u64;
+
+fn cost(_operator: &Operator) -> u64 {
+    1
+}
+
+/// Compiler middleware charging one point per operator.
+///
+/// A middleware instance can only transform a single module, every compilation needs a fresh one.
+/// Modules start with `u64::MAX` points, limits are applied per call by `metered`.
+pub fn middleware() -> Arc<Metering<Cost>> {
+    Arc::new(Metering::new(u64::MAX, cost as Cost))
+}
+
+pub fn set_limit(instance_id: u64, fuel: Option<u64>) {
+    match fuel {
+        Some(fuel) => {
+            LIMITS.insert(instance_id, fuel);
+        }
+        None => {
+            LIMITS.remove(&instance_id);
+        }
+    }
+}
+
+/// Drop the limit and the accounting of a terminated instance.
+pub fn forget(instance_id: u64) {
+    LIMITS.remove(&instance_id);
+    CONSUMED.remove(&instance_id);
+    CALL_FUEL.remove(&instance_id);
+}
+
+/// Fuel granted to the call `instance_id` is running, if the call was given its own instead of the limit of the instance.
+///
+/// Calls a guest makes to services are granted the same, a call cannot escape its fuel by calling another instance.
+pub fn call_fuel(instance_id: u64) -> Option<u64> {
+    CALL_FUEL.get(&instance_id).map(|fuel| *fuel)
+}
+
+/// Total fuel `instance_id` consumed, for billing.
+pub fn consumed(instance_id: u64) -> u64 {
+    CONSUMED.get(&instance_id).map_or(0, |consumed| *consumed)
+}
+
+/// The metering globals of an instance.
+///
+/// Reads them like `get_remaining_points` does, host imports only have the globals and not the `Instance`.
+#[derive(Clone)]
+pub struct Points {
+    remaining: Global,
+    exhausted: Global,
+}
+
+impl Points {
+    pub fn new(remaining: Global, exhausted: Global) -> Self {
+        Self { remaining, exhausted }
+    }
+
+    pub fn of(instance: &Instance) -> Result<Self> {
+        let remaining = instance.exports.get_global(REMAINING_POINTS)?.clone();
+        let exhausted = instance.exports.get_global(POINTS_EXHAUSTED)?.clone();
+        Ok(Self::new(remaining, exhausted))
+    }
+
+    pub fn get(&self) -> MeteringPoints {
+        match self.exhausted.get().i32() {
+            Some(exhausted) if exhausted > 0 => MeteringPoints::Exhausted,
+            _ => MeteringPoints::Remaining(self.remaining.get().i64().map_or(0, |points| points as u64)),
+        }
+    }
+
+    /// Set the remaining points, clearing the exhausted flag like `set_remaining_points`.
+    pub fn set(&self, points: u64) -> std::result::Result<(), RuntimeError> {
+        self.remaining.set(Val::I64(points as i64))?;
+        self.exhausted.set(Val::I32(0))
+    }
+}
+
+/// Run `f`, a call into `instance_id`, with `fuel` points or the limit of the instance.
+///
+/// Returns the result of the call and the fuel it consumed, a call trapping with its points exhausted is `Error::OutOfFuel`.
+/// Limited calls get a fresh budget, the points of an enclosing call into the same instance are restored after.
+pub fn metered<R, F>(points: &Points, instance_id: u64, fuel: Option<u64>, f: F) -> (Result<R>, u64)
+where
+    F: FnOnce() -> std::result::Result<R, RuntimeError>,
+{
+    let limit = fuel.or_else(|| LIMITS.get(&instance_id).map(|limit| *limit));
+    let before = remaining(points.get());
+    if let Some(limit) = limit {
+        if let Err(e) = points.set(limit) {
+            return (Err(e.into()), 0);
+        }
+    }
+
+    let enclosing = fuel.and_then(|fuel| CALL_FUEL.insert(instance_id, fuel));
+    let result = f();
+    if fuel.is_some() {
+        match enclosing {
+            Some(enclosing) => CALL_FUEL.insert(instance_id, enclosing),
+            None => CALL_FUEL.remove(&instance_id),
+        };
+    }
+    let exhausted = points.get() == MeteringPoints::Exhausted;
+    let after = remaining(points.get());
+    let consumed = limit.unwrap_or(before).saturating_sub(after);
+    if limit.is_some() {
+        if let Err(e) = points.set(before) {
+            return (Err(e.into()), consumed);
+        }
+    }
+    CONSUMED.upsert(instance_id, || consumed, |total| *total = total.saturating_add(consumed));
+
+    let result = result.map_err(|e| if exhausted { Error::OutOfFuel(limit.unwrap_or(before)) } else { e.into() });
+    (result, consumed)
+}
+
+fn remaining(points: MeteringPoints) -> u64 {
+    match points {
+        MeteringPoints::Remaining(points) => points,
+        MeteringPoints::Exhausted => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmer::{imports, Module};
+
+    use super::*;
+    use crate::backend::Backend;
+    use crate::testing::module;
+
+    fn instance() -> Instance {
+        let wasm = module(
+            r#"
+            (func (export "spin") (loop (br 0)))
+            (func (export "add") (param i32 i32) (result i32)
+                (i32.add (local.get 0) (local.get 1)))
+            "#,
+        );
+        let module = Module::new(&Backend::default().store().unwrap(), wasm).unwrap();
+        Instance::new(&module, &imports! {}).unwrap()
+    }
+
+    #[test]
+    fn exhausting_the_fuel_is_out_of_fuel() {
+        let instance = instance();
+        let points = Points::of(&instance).unwrap();
+        let spin = instance.exports.get_function("spin").unwrap();
+
+        let (result, consumed) = metered(&points, u64::MAX - 1, Some(100), || spin.call(&[]));
+        assert!(matches!(result, Err(Error::OutOfFuel(100))), "{:?}", result.err());
+        assert_eq!(consumed, 100);
+        assert_eq!(points.get(), MeteringPoints::Remaining(u64::MAX));
+    }
+
+    #[test]
+    fn calls_within_their_fuel_succeed() {
+        let instance = instance();
+        let points = Points::of(&instance).unwrap();
+        let add = instance.exports.get_function("add").unwrap();
+
+        let (result, consumed) = metered(&points, u64::MAX - 2, Some(100), || add.call(&[Val::I32(1), Val::I32(2)]));
+        assert_eq!(result.unwrap().to_vec(), vec![Val::I32(3)]);
+        assert!(consumed > 0 && consumed < 100, "{}", consumed);
+        assert_eq!(consumed, self::consumed(u64::MAX - 2));
+    }
+
+    #[test]
+    fn calls_grant_their_fuel_to_the_calls_they_make() {
+        let instance = instance();
+        let points = Points::of(&instance).unwrap();
+        let add = instance.exports.get_function("add").unwrap();
+        let instance_id = u64::MAX - 3;
+        let add = || add.call(&[Val::I32(1), Val::I32(2)]).map(|_| call_fuel(instance_id));
+
+        let (granted, _) = metered(&points, instance_id, Some(100), || {
+            let (nested, _) = metered(&points, instance_id, Some(50), add);
+            assert_eq!(nested.unwrap(), Some(50));
+            let (limited, _) = metered(&points, instance_id, None, add);
+            assert_eq!(limited.unwrap(), Some(100));
+            add()
+        });
+        assert_eq!(granted.unwrap(), Some(100));
+        assert_eq!(call_fuel(instance_id), None);
+    }
+
+    #[test]
+    fn forgotten_instances_have_no_limit_nor_accounting() {
+        let instance = instance();
+        let points = Points::of(&instance).unwrap();
+        let add = instance.exports.get_function("add").unwrap();
+        let instance_id = u64::MAX - 4;
+
+        set_limit(instance_id, Some(100));
+        let (result, _) = metered(&points, instance_id, None, || add.call(&[Val::I32(1), Val::I32(2)]));
+        assert!(result.is_ok() && consumed(instance_id) > 0);
+
+        forget(instance_id);
+        assert!(LIMITS.get(&instance_id).is_none());
+        assert_eq!(consumed(instance_id), 0);
+    }
+}
@@ -0,0 +1,288 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chashmap::CHashMap;
+use once_cell::sync::Lazy;
+use tokio::sync::Notify;
+
+use crate::error::Error;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Spawns a new instance for a pool and returns its id, the instance registers itself under the pool name.
+pub type Spawner = Arc<dyn Fn() -> Result<u64> + Send + Sync>;
+
+/// How long a call waits for an instance of a full pool before failing with `Error::Busy`.
+pub const LEASE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The instance whose call leased an instance, by leased instance id. Calls from the host have no entry.
+static LEASED_FOR: Lazy<CHashMap<u64, u64>> = Lazy::new(CHashMap::new);
+
+/// Instances serving one service name.
+///
+/// Instances serve one call at a time, so every call leases an idle instance.
+/// While all are busy the pool grows up to `max` instances through its spawner,
+/// past that calls wait for an instance to be released.
+///
+/// Calls an instance makes while serving a leased call, directly or through other instances,
+/// reenter it through the same lease instead of waiting for it, see `reenter`.
+pub struct InstancePool {
+    name: String,
+    inner: Mutex<Inner>,
+    /// Notified whenever an instance becomes idle
+    released: Notify,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Instance ids with whether they are serving a call
+    members: Vec<(u64, bool)>,
+    max: usize,
+    spawner: Option<Spawner>,
+    /// Instances being spawned, they count against `max`
+    spawning: usize,
+}
+
+/// A call served by an instance of a pool, the instance is released when the lease is dropped.
+pub struct Lease {
+    pool: Arc<InstancePool>,
+    instance_id: u64,
+    /// Reentering a call leased before, dropping it releases nothing
+    nested: bool,
+}
+
+impl InstancePool {
+    pub fn new<N: Into<String>>(name: N) -> Self {
+        Self { name: name.into(), inner: Mutex::new(Inner::default()), released: Notify::new() }
+    }
+
+    pub fn insert(&self, instance_id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.members.iter().any(|(id, _)| *id == instance_id) {
+            inner.members.push((instance_id, false));
+            self.released.notify_one();
+        }
+    }
+
+    pub fn remove(&self, instance_id: u64) {
+        self.inner.lock().unwrap().members.retain(|(id, _)| *id != instance_id);
+    }
+
+    /// Let the pool grow up to `max` instances with `spawner`, replacing the previous spawner.
+    pub fn set_spawner(&self, max: usize, spawner: Spawner) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.max = max;
+        inner.spawner = Some(spawner);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.lock().unwrap().members.is_empty()
+    }
+
+    /// An idle instance if there is one, else a busy one, without leasing it.
+    pub fn pick(&self) -> Option<u64> {
+        let inner = self.inner.lock().unwrap();
+        inner.members.iter().min_by_key(|(_, busy)| *busy).map(|(id, _)| *id)
+    }
+
+    /// Lease an idle instance for a call made by `caller`, spawning one if all are busy and the pool is not full.
+    ///
+    /// Returns `None` if every instance is busy and no instance could be spawned.
+    pub fn try_acquire(self: &Arc<Self>, caller: Option<u64>) -> Option<Lease> {
+        let spawner = {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(member) = inner.members.iter_mut().find(|(_, busy)| !*busy) {
+                member.1 = true;
+                return Some(self.lease(member.0, caller));
+            }
+            match inner.spawner.clone() {
+                Some(spawner) if inner.members.len() + inner.spawning < inner.max => {
+                    inner.spawning += 1;
+                    spawner
+                }
+                _ => return None,
+            }
+        };
+
+        // spawn without holding the lock, the new instance registers itself into this pool
+        let spawned = spawner();
+        let mut inner = self.inner.lock().unwrap();
+        inner.spawning -= 1;
+        match spawned {
+            Ok(instance_id) => {
+                debug!("pool <{}> grew to {} instances", self.name, inner.members.len());
+                // another call may have leased it in the meantime
+                let member = inner.members.iter_mut().find(|(id, busy)| *id == instance_id && !*busy)?;
+                member.1 = true;
+                Some(self.lease(instance_id, caller))
+            }
+            Err(e) => {
+                warn!("cannot grow pool <{}>: {}", self.name, e);
+                None
+            }
+        }
+    }
+
+    /// Like `try_acquire`, waiting up to `timeout` for an instance to be released if the pool is full.
+    pub async fn acquire(self: &Arc<Self>, caller: Option<u64>, timeout: Duration) -> Result<Lease> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            if let Some(lease) = self.try_acquire(caller) {
+                return Ok(lease);
+            }
+            // a release since trying left a permit, the wait returns right away
+            if tokio::time::timeout_at(deadline, self.released.notified()).await.is_err() {
+                return Err(Error::Busy(self.name.clone()));
+            }
+        }
+    }
+
+    /// Reuse the lease of an instance of this pool serving the call `caller` is making, if there is one.
+    ///
+    /// That is `caller` itself, or the instance whose call leased it, and so on. Waiting for it instead
+    /// would wait for the call waiting for this one. The lane of the instance runs the nested call once
+    /// the running one returned.
+    pub fn reenter(self: &Arc<Self>, caller: u64) -> Option<Lease> {
+        let mut chain = vec![caller];
+        while let Some(next) = LEASED_FOR.get(chain.last().unwrap()).map(|id| *id) {
+            if chain.contains(&next) {
+                break;
+            }
+            chain.push(next);
+        }
+        let inner = self.inner.lock().unwrap();
+        let instance_id = chain.into_iter().find(|id| inner.members.contains(&(*id, true)))?;
+        Some(Lease { pool: self.clone(), instance_id, nested: true })
+    }
+
+    fn lease(self: &Arc<Self>, instance_id: u64, caller: Option<u64>) -> Lease {
+        if let Some(caller) = caller {
+            LEASED_FOR.insert(instance_id, caller);
+        }
+        Lease { pool: self.clone(), instance_id, nested: false }
+    }
+}
+
+impl Lease {
+    pub fn instance_id(&self) -> u64 {
+        self.instance_id
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if self.nested {
+            return;
+        }
+        LEASED_FOR.remove(&self.instance_id);
+        let mut inner = self.pool.inner.lock().unwrap();
+        if let Some(member) = inner.members.iter_mut().find(|(id, _)| *id == self.instance_id) {
+            member.1 = false;
+            self.pool.released.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+
+    fn pool(members: &[u64]) -> Arc<InstancePool> {
+        let pool = Arc::new(InstancePool::new("test"));
+        for id in members {
+            pool.insert(*id);
+        }
+        pool
+    }
+
+    #[test]
+    fn leases_idle_instances_only() {
+        let pool = pool(&[1, 2]);
+        let first = pool.try_acquire(None).unwrap();
+        let second = pool.try_acquire(None).unwrap();
+        assert_eq!((first.instance_id(), second.instance_id()), (1, 2));
+        assert!(pool.try_acquire(None).is_none());
+
+        drop(first);
+        assert_eq!(pool.pick(), Some(1));
+        assert_eq!(pool.try_acquire(None).unwrap().instance_id(), 1);
+    }
+
+    #[test]
+    fn removed_instances_are_not_leased() {
+        let pool = pool(&[1, 2]);
+        let lease = pool.try_acquire(None).unwrap();
+        pool.remove(2);
+        assert!(pool.try_acquire(None).is_none());
+        pool.remove(1);
+        drop(lease);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn grows_up_to_max() {
+        let pool = pool(&[1]);
+        let next_id = Arc::new(AtomicU64::new(10));
+        let grown = Arc::downgrade(&pool);
+        pool.set_spawner(2, Arc::new(move || {
+            let id = next_id.fetch_add(1, Ordering::SeqCst);
+            grown.upgrade().unwrap().insert(id);
+            Ok(id)
+        }));
+
+        let first = pool.try_acquire(None).unwrap();
+        let spawned = pool.try_acquire(None).unwrap();
+        assert_eq!((first.instance_id(), spawned.instance_id()), (1, 10));
+        assert!(pool.try_acquire(None).is_none());
+    }
+
+    #[test]
+    fn failing_spawners_do_not_lease_busy_instances() {
+        let pool = pool(&[1]);
+        pool.set_spawner(4, Arc::new(|| Err(Error::Abi("no".to_string()))));
+        let _lease = pool.try_acquire(None).unwrap();
+        assert!(pool.try_acquire(None).is_none());
+    }
+
+    #[test]
+    fn nested_calls_reenter_the_leased_instance() {
+        let (outer, inner) = (pool(&[100]), pool(&[200]));
+        assert!(outer.reenter(100).is_none(), "idle instances are leased as usual");
+
+        // the host calls 100, which calls 200, which calls back into the pool of 100
+        let lease = outer.try_acquire(None).unwrap();
+        let called = inner.try_acquire(Some(100)).unwrap();
+        assert!(outer.try_acquire(Some(200)).is_none());
+        let nested = outer.reenter(200).unwrap();
+        assert_eq!(nested.instance_id(), 100);
+        assert_eq!(outer.reenter(100).unwrap().instance_id(), 100);
+
+        drop(nested);
+        assert!(outer.try_acquire(None).is_none(), "nested leases release nothing");
+        drop(called);
+        assert!(outer.reenter(200).is_none());
+        drop(lease);
+        assert_eq!(outer.try_acquire(None).unwrap().instance_id(), 100);
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_release() {
+        let pool = pool(&[1]);
+        let lease = pool.try_acquire(None).unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(lease);
+        });
+        assert_eq!(pool.acquire(None, Duration::from_secs(5)).await.unwrap().instance_id(), 1);
+    }
+
+    #[tokio::test]
+    async fn waiting_times_out_as_busy() {
+        let pool = pool(&[1]);
+        let _lease = pool.try_acquire(None).unwrap();
+        let result = pool.acquire(None, Duration::from_millis(20)).await;
+        assert!(matches!(result, Err(Error::Busy(ref name)) if name == "test"));
+    }
+}
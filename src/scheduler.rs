@@ -1,48 +1,430 @@
-use std::future::Future;
-use std::task::{Context, Poll};
-use std::pin::Pin;
-use std::ffi::c_void;
+use std::future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use wasmer::{Function, Val};
-use semi_async::{trampoline, AsyncResult};
+use once_cell::sync::Lazy;
+use wasmer::{Function, Instance, Memory, NativeFunc, Val};
+use semi_async::AsyncResult;
+use semi_async::host_callback::PendingCalls;
 use std::marker::PhantomData;
-use std::ops::Deref;
-use serde::Deserialize;
 use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
 
-type Result<T> = std::result::Result<T, crate::error::Error>;
+use we_codec::Format;
 
-fn call<F>(function: &Function, mut callback: F) where F: FnMut(Vec<u8>) {
-    let trampoline = trampoline::<F> as *mut c_void;
-    let user_data = &mut callback as *mut _ as *mut c_void;
-    function.call(&[
-        Val::I64(trampoline as i64),
-        Val::I64(user_data as i64)
-    ]);
+use crate::error::{decode_reply, Error};
+use crate::lane::LANES;
+use crate::memory::{GuestMemory, GuestPtr};
+use crate::metering;
+use crate::GLOBAL_INSTANCE_MAP;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Calls into guests waiting for their `callback`, owned by the callee instance id.
+pub static PENDING_CALLS: Lazy<PendingCalls> = Lazy::new(PendingCalls::new);
+
+/// A copy of host data in guest memory, allocated by `_wasm_malloc` and freed on drop.
+///
+/// Only used in jobs of the lane of the instance, like any other call into the guest.
+struct GuestBuffer {
+    free: NativeFunc<(i32, i32)>,
+    ptr: i32,
+    len: i32,
+}
+
+impl GuestBuffer {
+    fn new(instance: &Instance, data: &[u8]) -> Result<Self> {
+        let memory: &Memory = instance.exports.get_memory("memory")?;
+        let malloc: NativeFunc<i32, i32> = instance.exports.get_native_function("_wasm_malloc")?;
+        let free = instance.exports.get_native_function("_wasm_free")?;
+
+        let ptr = malloc.call(data.len() as i32)?;
+        if ptr == 0 {
+            return Err(Error::OutOfMemory(data.len() as u32));
+        }
+        let buffer = Self { free, ptr, len: data.len() as i32 };
+        GuestMemory::new(memory).write_bytes(GuestPtr::new(ptr as u32), data)?;
+        Ok(buffer)
+    }
+
+    /// `(ptr, len)` arguments to pass the buffer to a guest function
+    fn params(&self) -> [Val; 2] {
+        [Val::I32(self.ptr), Val::I32(self.len)]
+    }
+}
+
+impl Drop for GuestBuffer {
+    fn drop(&mut self) {
+        if let Err(e) = self.free.call(self.ptr, self.len) {
+            warn!("cannot free guest buffer: {}", e);
+        }
+    }
+}
+
+/// Give up on `request_id` once `timeout` elapses or `cancellation` is cancelled, unless `done` is cancelled first.
+///
+/// Giving up drops the pending continuation, tells the guest to drop the task serving the request
+/// and fails the call with `Error::Timeout` or `Error::Cancelled`.
+fn watch<F>(
+    instance_id: u64,
+    request_id: u64,
+    timeout: Option<Duration>,
+    cancellation: Option<CancellationToken>,
+    done: CancellationToken,
+    fail: F,
+) where
+    F: FnOnce(Error) + Send + 'static,
+{
+    tokio::spawn(async move {
+        let timeout = async move {
+            match timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => future::pending().await,
+            }
+        };
+        let cancelled = async move {
+            match cancellation {
+                Some(token) => token.cancelled().await,
+                None => future::pending().await,
+            }
+        };
+        let error = tokio::select! {
+            _ = done.cancelled() => return,
+            _ = timeout => Error::Timeout,
+            _ = cancelled => Error::Cancelled,
+        };
+        if PENDING_CALLS.cancel(request_id) {
+            cancel_guest(instance_id, request_id);
+            fail(error);
+        }
+    });
+}
+
+/// Tell the guest to drop the task serving `request_id`, guests without `_wasm_cancel` keep running it.
+fn cancel_guest(instance_id: u64, request_id: u64) {
+    let lane = match LANES.get(&instance_id) {
+        Some(lane) => lane.clone(),
+        None => return,
+    };
+    lane.post(move || {
+        let instance = match GLOBAL_INSTANCE_MAP.get(&instance_id) {
+            Some(instance) => instance.clone(),
+            None => return,
+        };
+        if let Ok(cancel) = instance.exports.get_native_function::<i64, i32>("_wasm_cancel") {
+            if let Err(e) = cancel.call(request_id as i64) {
+                warn!("cannot cancel request {} of #{}: {}", request_id, instance_id, e);
+            }
+        }
+    });
+}
+
+/// A call into an instance, run in its lane.
+struct Call {
+    instance_id: u64,
+    instance: Instance,
+    function: Function,
+    args: Option<Vec<u8>>,
+    format: Format,
+    fuel: Option<u64>,
+    fuel_consumed: Arc<AtomicU64>,
 }
 
-pub struct WasmFunctionExecution<'a, T> {
-    function: &'a Function,
+impl Call {
+    fn run(self, request_id: u64) -> Result<Box<[Val]>> {
+        let buffer = self.args.as_ref().map(|args| GuestBuffer::new(&self.instance, args)).transpose()?;
+        let mut params = buffer.as_ref().map_or_else(Vec::new, |buffer| buffer.params().to_vec());
+        params.push(Val::I64(request_id as i64));
+        self.set_format()?;
+        let points = metering::Points::of(&self.instance)?;
+
+        let (result, consumed) = metering::metered(&points, self.instance_id, self.fuel, || self.function.call(&params));
+        self.fuel_consumed.fetch_add(consumed, Ordering::SeqCst);
+        result
+    }
+
+    /// Select the format of the call in the guest, it defaults to bincode.
+    fn set_format(&self) -> Result<()> {
+        if self.format == Format::Bincode {
+            return Ok(());
+        }
+        let set_format = self
+            .instance
+            .exports
+            .get_native_function::<i32, i32>("_wasm_set_format")
+            .map_err(|_| Error::Abi(format!("guest does not support {} arguments", self.format)))?;
+        match set_format.call(self.format.tag() as i32)? {
+            0 => Err(Error::Abi(format!("guest does not support {} arguments", self.format))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Calls of an export of an instance, run in the lane of the instance.
+pub struct WasmFunctionExecution<T> {
+    instance_id: u64,
+    instance: Instance,
+    function: Function,
+    fuel: Option<u64>,
+    fuel_consumed: Arc<AtomicU64>,
+    timeout: Option<Duration>,
+    cancellation: Option<CancellationToken>,
+    format: Format,
     _return_type: PhantomData<T>
 }
 
-impl<'a, T> WasmFunctionExecution<'a, T> {
-    pub fn new(function: &'a Function) -> Self {
-        Self { function, _return_type: Default::default() }
+impl<T> WasmFunctionExecution<T> {
+    /// Calls of `function`, an export of `instance`, the instance `instance_id`.
+    pub fn new(instance_id: u64, instance: &Instance, function: &Function) -> Self {
+        Self {
+            instance_id,
+            instance: instance.clone(),
+            function: function.clone(),
+            fuel: None,
+            fuel_consumed: Default::default(),
+            timeout: None,
+            cancellation: None,
+            format: Format::Bincode,
+            _return_type: Default::default(),
+        }
+    }
+
+    /// Fail calls the guest did not reply to within `timeout` with `Error::Timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
     }
 
-    pub fn call(&self) -> AsyncResult<Result<T>> where T: DeserializeOwned {
+    /// Fail calls still pending when `token` is cancelled with `Error::Cancelled`.
+    #[allow(dead_code)]
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Encode arguments and decode replies in `format` instead of bincode.
+    ///
+    /// Calls fail with `Error::Abi` if the guest does not support `format`.
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Limit every call to `fuel`, instead of the limit of the module.
+    pub fn with_fuel(mut self, fuel: u64) -> Self {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    /// Fuel consumed by the calls the guest accepted so far, replies running later are billed to the instance only.
+    pub fn fuel_consumed(&self) -> u64 {
+        self.fuel_consumed.load(Ordering::SeqCst)
+    }
+
+    /// Call a `(request_id)` export.
+    #[allow(dead_code)]
+    pub fn call(&self) -> AsyncResult<Result<T>> where T: DeserializeOwned + Send + 'static {
+        let format = self.format;
+        self.call_decode(None, self.timeout, move |data| format.decode(&data).map_err(|e| e.into()))
+    }
+
+    /// Like `call`, failing with `Error::Timeout` if the guest did not reply within `timeout`.
+    #[allow(dead_code)]
+    pub fn call_with_timeout(&self, timeout: Duration) -> AsyncResult<Result<T>> where T: DeserializeOwned + Send + 'static {
+        let format = self.format;
+        self.call_decode(None, Some(timeout), move |data| format.decode(&data).map_err(|e| e.into()))
+    }
+
+    /// Call a `(args_ptr, args_len, request_id)` export with serialized `args`.
+    ///
+    /// The arguments are copied into memory allocated by the guest `_wasm_malloc`,
+    /// and freed with `_wasm_free` once the call returns.
+    #[allow(dead_code)]
+    pub fn call_with<A>(&self, args: &A) -> AsyncResult<Result<T>> where A: Serialize, T: DeserializeOwned + Send + 'static {
+        let format = self.format;
+        let args = match format.encode(args) {
+            Ok(args) => args,
+            Err(e) => return AsyncResult::ready(Err(e.into())),
+        };
+        self.call_decode(Some(&args), self.timeout, move |data| format.decode(&data).map_err(|e| e.into()))
+    }
+
+    /// Call the function and resolve with the response bytes as they are.
+    pub fn call_bytes(&self) -> AsyncResult<Result<Vec<u8>>> {
+        self.call_decode(None, self.timeout, Ok)
+    }
+
+    /// Like `call_bytes`, passing already serialized `args`.
+    pub fn call_bytes_with(&self, args: &[u8]) -> AsyncResult<Result<Vec<u8>>> {
+        self.call_decode(Some(args), self.timeout, Ok)
+    }
+
+    fn call_decode<U, D>(&self, args: Option<&[u8]>, timeout: Option<Duration>, decode: D) -> AsyncResult<Result<U>>
+    where
+        U: Send + 'static,
+        D: FnOnce(Vec<u8>) -> Result<U> + Send + 'static,
+    {
         let result = AsyncResult::default();
         let inner = result.clone_inner();
-        call(self.function, move |data: Vec<u8>| {
-            let mut inner = inner.deref().borrow_mut();
-            inner.set_value(bincode::deserialize(&data).map_err(|e| e.into()));
-
-            let task_op = inner.waker_ref();
-            if task_op.is_some() {
-                task_op.unwrap().wake_by_ref();
-            };
-        });
+        let done = CancellationToken::new();
+        let replied = done.clone();
+        let on_reply = move |data: Vec<u8>| {
+            semi_async::resolve(&inner, decode_reply(&data).and_then(decode));
+            replied.cancel();
+        };
+        let inner = result.clone_inner();
+        let request_id = self.start(args, on_reply, |_| Ok(()), move |e| semi_async::resolve(&inner, Err(e)));
+        if timeout.is_some() || self.cancellation.is_some() {
+            let inner = result.clone_inner();
+            let fail = move |e| semi_async::resolve(&inner, Err(e));
+            watch(self.instance_id, request_id, timeout, self.cancellation.clone(), done, fail);
+        }
         result
     }
-}
\ No newline at end of file
+
+    /// Call the function and hand the raw reply to `callback`, without decoding it, see `error::decode_reply`.
+    ///
+    /// `accept` checks the results of the export, the call fails with `fail` if it refuses them,
+    /// if the export traps or if the instance is gone. At most one of `callback` and `fail` is called.
+    pub fn call_raw<F, A, E>(&self, args: Option<&[u8]>, callback: F, accept: A, fail: E)
+    where
+        F: FnOnce(Vec<u8>) + Send + 'static,
+        A: FnOnce(&[Val]) -> Result<()> + Send + 'static,
+        E: FnOnce(Error) + Send + 'static,
+    {
+        self.start(args, callback, accept, fail);
+    }
+
+    /// Register `callback` for the reply and post the call to the lane of the instance, returns the request id.
+    fn start<F, A, E>(&self, args: Option<&[u8]>, callback: F, accept: A, fail: E) -> u64
+    where
+        F: FnOnce(Vec<u8>) + Send + 'static,
+        A: FnOnce(&[Val]) -> Result<()> + Send + 'static,
+        E: FnOnce(Error) + Send + 'static,
+    {
+        let request_id = PENDING_CALLS.register(self.instance_id, callback);
+        let lane = match LANES.get(&self.instance_id) {
+            Some(lane) => lane.clone(),
+            None => {
+                PENDING_CALLS.cancel(request_id);
+                fail(Error::Terminated(self.instance_id));
+                return request_id;
+            }
+        };
+        let call = Call {
+            instance_id: self.instance_id,
+            instance: self.instance.clone(),
+            function: self.function.clone(),
+            args: args.map(<[u8]>::to_vec),
+            format: self.format,
+            fuel: self.fuel,
+            fuel_consumed: self.fuel_consumed.clone(),
+        };
+        lane.post(move || {
+            // the call may have timed out or been cancelled while queued, or its reply may have been lost
+            if let Err(e) = call.run(request_id).and_then(|results| accept(&results)) {
+                if PENDING_CALLS.cancel(request_id) {
+                    fail(e);
+                }
+            }
+        });
+        request_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manager::InstanceManager;
+    use crate::manifest::Manifest;
+    use crate::testing::{guest, manager, spec, ECHO};
+
+    /// An export never replying.
+    const SILENT: &str = r#"(func (export "silent") (param i64))"#;
+
+    fn deploy(manager: &Arc<InstanceManager>, dir: &std::path::Path, fields: &str) -> (u64, Instance) {
+        let manifest = Manifest { modules: vec![spec(dir, "calls", &guest(0, fields))], ..Default::default() };
+        let instance_id = manager.deploy(&manifest).unwrap()[0];
+        (instance_id, manager.instance(instance_id).unwrap())
+    }
+
+    #[tokio::test]
+    async fn calls_are_replied_from_the_lane() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager();
+        let manifest = Manifest { modules: vec![spec(dir.path(), "echo", &guest(0, ECHO))], ..Default::default() };
+        let instance_id = manager.deploy(&manifest).unwrap()[0];
+
+        let instance = manager.instance(instance_id).unwrap();
+        let echo = instance.exports.get_function("echo").unwrap();
+        let execution = WasmFunctionExecution::<()>::new(instance_id, &instance, echo);
+        let replies = (0..16u8).map(|i| execution.call_bytes_with(&[i; 3])).collect::<Vec<_>>();
+        for (i, reply) in replies.into_iter().enumerate() {
+            assert_eq!(reply.await.unwrap(), [i as u8; 3]);
+        }
+        assert!(execution.fuel_consumed() > 0);
+
+        manager.terminate(instance_id).unwrap();
+        assert!(matches!(execution.call_bytes_with(b"late").await, Err(Error::Terminated(id)) if id == instance_id));
+    }
+
+    #[tokio::test]
+    async fn typed_calls_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager();
+        let (instance_id, instance) = deploy(&manager, dir.path(), ECHO);
+        let echo = instance.exports.get_function("echo").unwrap();
+        let execution = WasmFunctionExecution::<(u32, String)>::new(instance_id, &instance, echo);
+        let args = (7, "seven".to_string());
+        assert_eq!(execution.call_with(&args).await.unwrap(), args);
+        // the guest does not declare FEATURE_FORMATS
+        let execution = execution.with_format(Format::Json);
+        assert!(matches!(execution.call_with(&args).await, Err(Error::Abi(_))));
+    }
+
+    #[tokio::test]
+    async fn pending_calls_time_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager();
+        let (instance_id, instance) = deploy(&manager, dir.path(), SILENT);
+        let silent = instance.exports.get_function("silent").unwrap();
+        let execution = WasmFunctionExecution::<()>::new(instance_id, &instance, silent);
+        let reply = execution.call_with_timeout(Duration::from_millis(20)).await;
+        assert!(matches!(reply, Err(Error::Timeout)), "{:?}", reply);
+        let reply = execution.with_timeout(Duration::from_millis(20)).call().await;
+        assert!(matches!(reply, Err(Error::Timeout)), "{:?}", reply);
+    }
+
+    #[tokio::test]
+    async fn pending_calls_are_cancelled() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager();
+        let (instance_id, instance) = deploy(&manager, dir.path(), SILENT);
+        let silent = instance.exports.get_function("silent").unwrap();
+        let token = CancellationToken::new();
+        let execution = WasmFunctionExecution::<()>::new(instance_id, &instance, silent).with_cancellation(token.clone());
+        let reply = execution.call();
+        token.cancel();
+        let reply = reply.await;
+        assert!(matches!(reply, Err(Error::Cancelled)), "{:?}", reply);
+        assert!(matches!(execution.call().await, Err(Error::Cancelled)));
+    }
+
+    #[tokio::test]
+    async fn calls_are_limited_to_their_fuel() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager();
+        let spin = r#"(func (export "spin") (param i64) (loop $spin (br $spin)))"#;
+        let (instance_id, instance) = deploy(&manager, dir.path(), &format!("{}{}", ECHO, spin));
+        let spin = instance.exports.get_function("spin").unwrap();
+        let reply = WasmFunctionExecution::<()>::new(instance_id, &instance, spin).with_fuel(1000).call().await;
+        assert!(matches!(reply, Err(Error::OutOfFuel(1000))), "{:?}", reply);
+
+        // the instance keeps serving calls within their fuel
+        let echo = instance.exports.get_function("echo").unwrap();
+        let execution = WasmFunctionExecution::<()>::new(instance_id, &instance, echo).with_fuel(1000);
+        assert_eq!(execution.call_bytes_with(b"fuel").await.unwrap(), b"fuel");
+    }
+}
@@ -0,0 +1,319 @@
+use std::sync::{Arc, Mutex};
+
+use chashmap::CHashMap;
+use once_cell::sync::Lazy;
+use tokio::runtime::Handle;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use wasmer::Val;
+use we_codec::Format;
+use we_proto::Request;
+
+use crate::error::{decode_reply, Error};
+use crate::metering;
+use crate::pool::{InstancePool, Lease, Spawner, LEASE_TIMEOUT};
+use crate::scheduler::WasmFunctionExecution;
+use crate::GLOBAL_INSTANCE_MAP;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Serves raw arguments in a format, replying in the same format.
+pub type NativeHandler = Arc<dyn Fn(Format, &[u8]) -> Result<Vec<u8>> + Send + Sync>;
+
+pub static GLOBAL_SERVICE_REGISTRY: Lazy<ServiceRegistry> = Lazy::new(ServiceRegistry::default);
+
+/// Maps `(name, method)` pairs to the handler serving them.
+///
+/// Native handlers take precedence over guest instances registered under the same name,
+/// for guest instances `method` is the name of the export to call.
+/// Instances registered under the same name form an `InstancePool`, every call gets an idle one.
+#[derive(Default)]
+pub struct ServiceRegistry {
+    natives: CHashMap<(String, String), NativeHandler>,
+    pools: CHashMap<String, Arc<InstancePool>>,
+}
+
+impl ServiceRegistry {
+    pub fn register_native<N, M, A, R, F>(&self, name: N, method: M, handler: F)
+    where
+        N: Into<String>,
+        M: Into<String>,
+        A: DeserializeOwned,
+        R: Serialize,
+        F: Fn(A) -> R + Send + Sync + 'static,
+    {
+        self.register_native_raw(name, method, move |format: Format, args: &[u8]| {
+            let args = format.decode(args)?;
+            Ok(format.encode(&handler(args))?)
+        })
+    }
+
+    pub fn register_native_raw<N, M, F>(&self, name: N, method: M, handler: F)
+    where
+        N: Into<String>,
+        M: Into<String>,
+        F: Fn(Format, &[u8]) -> Result<Vec<u8>> + Send + Sync + 'static,
+    {
+        self.natives
+            .insert((name.into(), method.into()), Arc::new(handler));
+    }
+
+    pub fn register_instance<N: Into<String>>(&self, name: N, instance_id: u64) {
+        self.pool(name.into()).insert(instance_id);
+    }
+
+    pub fn unregister_instance(&self, name: &str, instance_id: u64) {
+        if let Some(pool) = self.pools.get(name) {
+            pool.remove(instance_id);
+        }
+    }
+
+    /// Let the pool of `name` spawn up to `max` instances while all of them are busy.
+    pub fn register_pool<N: Into<String>>(&self, name: N, max: usize, spawner: Spawner) {
+        self.pool(name.into()).set_spawner(max, spawner);
+    }
+
+    /// The pool of `name`, created empty on first use. Pools are never removed.
+    fn pool(&self, name: String) -> Arc<InstancePool> {
+        if let Some(pool) = self.pools.get(&name) {
+            return pool.clone();
+        }
+        self.pools.upsert(name.clone(), || Arc::new(InstancePool::new(name.clone())), |_| {});
+        self.pools.get(&name).map(|pool| pool.clone()).expect("pools are never removed")
+    }
+
+    /// An instance serving `name`, idle if there is one.
+    pub fn instance(&self, name: &str) -> Option<u64> {
+        self.pools.get(name)?.pick()
+    }
+
+    /// The pool of `name`, if it has instances.
+    pub fn pool_serving(&self, name: &str) -> Option<Arc<InstancePool>> {
+        // the map guard must be released first, spawning registers the new instance
+        let pool = self.pools.get(name).map(|pool| pool.clone())?;
+        if pool.is_empty() {
+            return None;
+        }
+        Some(pool)
+    }
+
+    /// Dispatch a call `caller` makes with `args` in `format`, `respond` is called at most once with the response
+    /// in the same format. `caller` is the calling instance, `None` for the host.
+    ///
+    /// Calls to a guest service wait for an idle instance if all are busy, up to `LEASE_TIMEOUT`,
+    /// unless they reenter an instance already serving the call `caller` is making.
+    /// They are granted the fuel of the call `caller` is making, if it was given its own, see `metering::call_fuel`.
+    pub fn dispatch<F>(&self, caller: Option<u64>, name: &str, method: &str, format: Format, args: &[u8], respond: F)
+    where
+        F: FnOnce(Result<Vec<u8>>) + Send + 'static,
+    {
+        let native = self
+            .natives
+            .get(&(name.to_string(), method.to_string()))
+            .map(|handler| handler.clone());
+        if let Some(handler) = native {
+            return respond(handler(format, args));
+        }
+
+        let pool = match self.pool_serving(name) {
+            Some(pool) => pool,
+            None => return respond(Err(Error::ServiceNotFound(name.to_string(), method.to_string()))),
+        };
+        // read now, the call of `caller` may be over once an instance is leased
+        let fuel = caller.and_then(metering::call_fuel);
+        if let Some(lease) = caller.and_then(|caller| pool.reenter(caller)).or_else(|| pool.try_acquire(caller)) {
+            return call_leased(lease, fuel, name, method, format, args, respond);
+        }
+        // wait off the calling thread, it may be running a guest
+        let runtime = match Handle::try_current() {
+            Ok(runtime) => runtime,
+            Err(_) => return respond(Err(Error::Busy(name.to_string()))),
+        };
+        let (name, method, args) = (name.to_string(), method.to_string(), args.to_vec());
+        runtime.spawn(async move {
+            match pool.acquire(caller, LEASE_TIMEOUT).await {
+                Ok(lease) => call_leased(lease, fuel, &name, &method, format, &args, respond),
+                Err(e) => respond(Err(e)),
+            }
+        });
+    }
+}
+
+/// Call `method` on the instance `lease` holds with `fuel` or the limit of the instance,
+/// the instance stays leased until the call is answered or dropped.
+fn call_leased<F>(lease: Lease, fuel: Option<u64>, name: &str, method: &str, format: Format, args: &[u8], respond: F)
+where
+    F: FnOnce(Result<Vec<u8>>) + Send + 'static,
+{
+    let instance_id = lease.instance_id();
+    let instance = match GLOBAL_INSTANCE_MAP.get(&instance_id) {
+        Some(instance) => instance.clone(),
+        None => return respond(Err(Error::ServiceNotFound(name.to_string(), method.to_string()))),
+    };
+    let respond = move |response| {
+        let _lease = lease;
+        respond(response)
+    };
+    let execution = |function| {
+        let execution = WasmFunctionExecution::<()>::new(instance_id, &instance, function);
+        match fuel {
+            Some(fuel) => execution.with_fuel(fuel),
+            None => execution,
+        }
+    };
+    if let Ok(dispatch) = instance.exports.get_function("_wasm_dispatch") {
+        return dispatch_guest(execution(dispatch), name, method, format, args, respond);
+    }
+
+    let function = match instance.exports.get_function(method) {
+        Ok(function) => function,
+        Err(_) => return respond(Err(Error::ServiceNotFound(name.to_string(), method.to_string()))),
+    };
+
+    // exports taking no arguments only have the `request_id` parameter
+    let args = if function.ty().params().len() > 1 { Some(args) } else { None };
+    let respond = Responder::new(respond);
+    let (on_reply, on_error) = (respond.clone(), respond);
+    execution(function).with_format(format).call_raw(
+        args,
+        move |data| on_reply.respond(decode_reply(&data)),
+        |_| Ok(()),
+        move |e| on_error.respond(Err(e)),
+    );
+}
+
+/// Shares a `respond` continuation between a guest reply and the error path of the call.
+struct Responder<F>(Arc<Mutex<Option<F>>>);
+
+impl<F> Clone for Responder<F> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<F: FnOnce(Result<Vec<u8>>)> Responder<F> {
+    fn new(respond: F) -> Self {
+        Self(Arc::new(Mutex::new(Some(respond))))
+    }
+
+    fn respond(&self, result: Result<Vec<u8>>) {
+        let respond = self.0.lock().unwrap().take();
+        if let Some(respond) = respond {
+            respond(result)
+        }
+    }
+}
+
+/// Serve a call with the `_wasm_dispatch` export of a guest registering services via `we_rt::serve`.
+fn dispatch_guest<F>(
+    dispatch: WasmFunctionExecution<()>,
+    name: &str,
+    method: &str,
+    format: Format,
+    args: &[u8],
+    respond: F,
+) where
+    F: FnOnce(Result<Vec<u8>>) + Send + 'static,
+{
+    let request = Request { service: name, method, format, args }.encode();
+    let not_found = Error::ServiceNotFound(name.to_string(), method.to_string());
+    // the guest returns 0 if it serves no such service
+    let accept = move |ret: &[Val]| match ret.first().and_then(|v| v.i32()) {
+        Some(0) => Err(not_found),
+        _ => Ok(()),
+    };
+    let respond = Responder::new(respond);
+    let (on_reply, on_error) = (respond.clone(), respond);
+    dispatch.call_raw(
+        Some(&request),
+        move |data| on_reply.respond(decode_reply(&data)),
+        accept,
+        move |e| on_error.respond(Err(e)),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use we_proto::ErrorCode;
+
+    use super::*;
+    use crate::manifest::Manifest;
+    use crate::testing::{guest, manager, spec, ECHO};
+
+    const INVOKE: &str = r#"(import "__wasm_everything_runtime__" "invoke" (func $invoke (param i32 i32 i64)))"#;
+
+    /// `forward` calls `service::method` with its arguments, under its own request id,
+    /// and `_wasm_resolve` replies the response as it is. Needs `INVOKE`.
+    fn forward(service: &str, method: &str) -> String {
+        assert_eq!((service.len(), method.len()), (8, 4), "the envelope is laid out for these lengths");
+        FORWARD.replace("{service}", service).replace("{method}", method)
+    }
+
+    const FORWARD: &str = r#"
+        ;; `Request` envelope up to the length of `args`: the service, the method and `Format::Bincode`
+        (data (i32.const 64) "\08\00\00\00\00\00\00\00{service}\04\00\00\00\00\00\00\00{method}\00\00\00\00")
+        (func (export "forward") (param $ptr i32) (param $len i32) (param $id i64)
+            (local $request i32)
+            (local $i i32)
+            (local.set $request (call $malloc (i32.add (local.get $len) (i32.const 40))))
+            (i64.store (local.get $request) (i64.load (i32.const 64)))
+            (i64.store (i32.add (local.get $request) (i32.const 8)) (i64.load (i32.const 72)))
+            (i64.store (i32.add (local.get $request) (i32.const 16)) (i64.load (i32.const 80)))
+            (i64.store (i32.add (local.get $request) (i32.const 24)) (i64.load (i32.const 88)))
+            (i64.store (i32.add (local.get $request) (i32.const 32)) (i64.extend_i32_u (local.get $len)))
+            (block $copied
+                (loop $copy
+                    (br_if $copied (i32.ge_u (local.get $i) (local.get $len)))
+                    (i32.store8
+                        (i32.add (i32.add (local.get $request) (i32.const 40)) (local.get $i))
+                        (i32.load8_u (i32.add (local.get $ptr) (local.get $i))))
+                    (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                    (br $copy)))
+            (call $invoke (local.get $request) (i32.add (local.get $len) (i32.const 40)) (local.get $id)))
+        (func (export "_wasm_resolve") (param $id i64) (param $ptr i32) (param $len i32) (result i32)
+            (call $callback (local.get $id) (local.get $ptr) (local.get $len))
+            (i32.const 1))
+    "#;
+
+    #[tokio::test]
+    async fn nested_calls_reenter_their_leased_instance() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager();
+        let spec = spec(dir.path(), "selfcall", &guest(0, &format!("{}{}{}", INVOKE, ECHO, forward("selfcall", "echo"))));
+        assert_eq!((spec.instances, spec.max_instances), (1, None));
+        manager.deploy(&Manifest { modules: vec![spec], ..Default::default() }).unwrap();
+
+        let (sender, reply) = tokio::sync::oneshot::channel();
+        GLOBAL_SERVICE_REGISTRY.dispatch(None, "selfcall", "forward", Format::Bincode, b"nested", move |response| {
+            let _ = sender.send(response);
+        });
+        let reply = tokio::time::timeout(Duration::from_secs(5), reply).await.expect("the nested call waited for a lease");
+        assert_eq!(reply.unwrap().unwrap(), b"nested");
+
+        // the nested call released nothing, the outer one released the instance
+        let pool = GLOBAL_SERVICE_REGISTRY.pool_serving("selfcall").unwrap();
+        assert!(pool.try_acquire(None).is_some());
+    }
+
+    #[tokio::test]
+    async fn calls_are_granted_the_fuel_of_their_caller() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager();
+        let spin = r#"
+            (import "__wasm_everything_runtime__" "callback" (func $callback (param i64 i32 i32)))
+            (func (export "spin") (param i32 i32 i64) (loop (br 0)))
+        "#;
+        let spec = spec(dir.path(), "spinning", &guest(0, &format!("{}{}{}", INVOKE, spin, forward("spinning", "spin"))));
+        let id = manager.deploy(&Manifest { modules: vec![spec], ..Default::default() }).unwrap()[0];
+        let instance = manager.instance(id).unwrap();
+        let forward = instance.exports.get_function("forward").unwrap();
+
+        // the module is unlimited, only the fuel of the forwarding call stops the spinning one
+        let execution = WasmFunctionExecution::<()>::new(id, &instance, forward).with_fuel(100_000);
+        let reply = tokio::time::timeout(Duration::from_secs(5), execution.call_bytes_with(b"spin")).await.unwrap();
+        assert!(matches!(reply, Err(Error::Remote { code: ErrorCode::OutOfFuel, .. })), "{:?}", reply);
+        assert_eq!(metering::call_fuel(id), None);
+    }
+}
@@ -0,0 +1,108 @@
+//! Guests written in WAT and managers running them, for the tests of the host.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use wasmer::wat2wasm;
+use we_proto::abi::{descriptor, ABI_SECTION};
+
+use crate::backend::Backend;
+use crate::manager::InstanceManager;
+use crate::manifest::ModuleSpec;
+
+/// What every guest exports, `NAME` is "test" and `_wasm_malloc` never frees. See `RESOLVE` for `_wasm_resolve`.
+const PRELUDE: &str = r#"
+    (memory (export "memory") 1)
+    (global $heap (mut i32) (i32.const 1024))
+    (global $id (mut i64) (i64.const 0))
+    (global (export "NAME") i32 (i32.const 16))
+    (data (i32.const 16) "\20\00\00\00")
+    (data (i32.const 32) "test\00")
+    (func (export "set_instance_id") (param i64) (result i32)
+        (global.set $id (local.get 0))
+        (i32.const 1))
+    (func (export "get_instance_id") (result i64)
+        (global.get $id))
+    (func $malloc (export "_wasm_malloc") (param i32) (result i32)
+        (local $ptr i32)
+        (local.set $ptr (global.get $heap))
+        (global.set $heap (i32.add (global.get $heap) (local.get 0)))
+        (local.get $ptr))
+    (func (export "_wasm_free") (param i32 i32))
+"#;
+
+/// `_wasm_resolve` of guests not defining their own, it drops the replies.
+const RESOLVE: &str = r#"
+    (func (export "_wasm_resolve") (param i64 i32 i32) (result i32)
+        (i32.const 1))
+"#;
+
+/// An `echo` export replying its arguments, `fields` of a guest.
+pub const ECHO: &str = r#"
+    (import "__wasm_everything_runtime__" "callback" (func $callback (param i64 i32 i32)))
+    (func (export "echo") (param $ptr i32) (param $len i32) (param $id i64)
+        (local $reply i32)
+        (local $i i32)
+        ;; `Response::Ok(args)` envelope: variant, length and bytes
+        (local.set $reply (call $malloc (i32.add (local.get $len) (i32.const 12))))
+        (i32.store (local.get $reply) (i32.const 0))
+        (i64.store (i32.add (local.get $reply) (i32.const 4)) (i64.extend_i32_u (local.get $len)))
+        (block $copied
+            (loop $copy
+                (br_if $copied (i32.ge_u (local.get $i) (local.get $len)))
+                (i32.store8
+                    (i32.add (i32.add (local.get $reply) (i32.const 12)) (local.get $i))
+                    (i32.load8_u (i32.add (local.get $ptr) (local.get $i))))
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br $copy)))
+        (call $callback (local.get $id) (local.get $reply) (i32.add (local.get $len) (i32.const 12))))
+"#;
+
+/// A guest declaring `features`, with `fields` ahead of the exports every guest has, imports go there.
+pub fn guest(features: u32, fields: &str) -> Vec<u8> {
+    let resolve = if fields.contains("\"_wasm_resolve\"") { "" } else { RESOLVE };
+    with_section(module(&format!("{}{}{}", fields, PRELUDE, resolve)), ABI_SECTION, &descriptor(features))
+}
+
+pub fn module(fields: &str) -> Vec<u8> {
+    wat2wasm(format!("(module {})", fields).as_bytes()).unwrap().into_owned()
+}
+
+/// Append the custom section `name` to `wasm`.
+pub fn with_section(mut wasm: Vec<u8>, name: &str, payload: &[u8]) -> Vec<u8> {
+    let mut section = leb128(name.len());
+    section.extend_from_slice(name.as_bytes());
+    section.extend_from_slice(payload);
+    wasm.push(0);
+    wasm.extend(leb128(section.len()));
+    wasm.extend(section);
+    wasm
+}
+
+fn leb128(mut value: usize) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// A manager compiling with the default backend, without cache or key-value store. Log records are dropped.
+pub fn manager() -> Arc<InstanceManager> {
+    let (log_channel, _) = tokio::sync::mpsc::unbounded_channel();
+    Arc::new(InstanceManager::new(Backend::default(), log_channel))
+}
+
+/// Write `wasm` to `dir` and describe it as the module `name`.
+pub fn spec(dir: &Path, name: &str, wasm: &[u8]) -> ModuleSpec {
+    let path = dir.join(name).with_extension("wasm");
+    std::fs::write(&path, wasm).unwrap();
+    let mut spec = ModuleSpec::from_path(path);
+    spec.name = Some(name.to_string());
+    spec
+}
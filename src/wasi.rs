@@ -0,0 +1,32 @@
+use wasmer::{ImportObject, Module};
+use wasmer_wasi::WasiState;
+
+use crate::error::Error;
+use crate::manifest::WasiSpec;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Whether `module` imports any version of WASI.
+pub fn is_wasi_module(module: &Module) -> bool {
+    wasmer_wasi::get_wasi_version(module, false).is_some()
+}
+
+/// WASI imports for one instance of `module`, granting only the capabilities in `spec`.
+///
+/// Every instance gets its own WASI state, instances of a module share nothing but the preopened directories.
+pub fn import_object(module: &Module, program: &str, spec: &WasiSpec) -> Result<ImportObject> {
+    let mut state = WasiState::new(program);
+    state.args(&spec.args).envs(&spec.env);
+    for dir in spec.preopen.iter() {
+        state.preopen_dir(dir).map_err(wasi_error)?;
+    }
+    for (alias, dir) in spec.map_dirs.iter() {
+        state.map_dir(alias, dir).map_err(wasi_error)?;
+    }
+    let mut env = state.finalize().map_err(wasi_error)?;
+    env.import_object(module).map_err(wasi_error)
+}
+
+fn wasi_error<E: std::fmt::Display>(e: E) -> Error {
+    Error::Wasi(e.to_string())
+}
@@ -0,0 +1,47 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::manager::InstanceManager;
+
+/// Rebuilds usually write a module in several steps, wait for them to settle before reloading.
+const DEBOUNCE_DELAY: Duration = Duration::from_millis(500);
+
+/// Reload modules through `manager` whenever one of `paths` changes on disk.
+///
+/// Watching stops when the returned watcher is dropped.
+pub fn watch(manager: Arc<InstanceManager>, paths: &[PathBuf]) -> anyhow::Result<RecommendedWatcher> {
+    let paths = paths.iter().map(|path| path.canonicalize()).collect::<Result<HashSet<_>, _>>()?;
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::watcher(tx, DEBOUNCE_DELAY)?;
+    // watch the directories, editors and linkers often replace the file instead of writing it
+    let dirs = paths.iter().filter_map(|path| path.parent()).collect::<HashSet<_>>();
+    for dir in dirs {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+
+    std::thread::spawn(move || {
+        for event in rx {
+            let path = match event {
+                DebouncedEvent::Create(path) | DebouncedEvent::Write(path) | DebouncedEvent::Rename(_, path) => path,
+                DebouncedEvent::Error(e, path) => {
+                    warn!("cannot watch {:?}: {}", path, e);
+                    continue;
+                }
+                _ => continue,
+            };
+            if !paths.contains(&path) {
+                continue;
+            }
+            match manager.reload(&path) {
+                Ok(instance_ids) => info!("reloaded {}, new instances {:?}", path.display(), instance_ids),
+                Err(e) => error!("cannot reload {}: {}", path.display(), e),
+            }
+        }
+    });
+    Ok(watcher)
+}
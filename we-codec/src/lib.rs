@@ -0,0 +1,328 @@
+//! Serialization formats of the host/guest wire protocol.
+//!
+//! Every call carries the `Format` tag of its arguments, the reply is encoded in the same format.
+//! The envelopes around payloads have a fixed layout, see `we_proto::envelope`.
+//!
+//! The codecs need std, bincode 1.x does, they are behind the default `std` feature.
+//! Without it the crate is `no_std` and only knows formats and their tags.
+#![no_std]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+use alloc::format;
+use alloc::string::String;
+#[cfg(feature = "std")]
+use alloc::string::ToString;
+#[cfg(feature = "std")]
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A format tag this version does not know
+    UnknownFormat(u32),
+    /// A value failed to encode or decode
+    Codec { format: Format, message: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnknownFormat(tag) => write!(f, "unknown format tag {}", tag),
+            Error::Codec { format, message } => write!(f, "{} error {}", format, message),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+#[cfg(feature = "std")]
+impl Error {
+    fn codec<E: fmt::Display>(format: Format, e: E) -> Self {
+        Error::Codec { format, message: e.to_string() }
+    }
+}
+
+/// A serialization format, `Format` dispatches to the codec of a tag.
+#[cfg(feature = "std")]
+pub trait Codec {
+    const FORMAT: Format;
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>>;
+
+    fn decode<'de, T: Deserialize<'de>>(data: &'de [u8]) -> Result<T>;
+}
+
+/// bincode 1.x with its default options, the format of Rust guests.
+#[cfg(feature = "std")]
+pub struct Bincode;
+
+/// JSON, arguments are an array of the call's arguments.
+#[cfg(feature = "std")]
+pub struct Json;
+
+/// MessagePack, structs are maps keyed by field name.
+#[cfg(feature = "std")]
+pub struct MessagePack;
+
+/// CBOR, structs are maps keyed by field name.
+#[cfg(feature = "std")]
+pub struct Cbor;
+
+#[cfg(feature = "std")]
+impl Codec for Bincode {
+    const FORMAT: Format = Format::Bincode;
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        bincode::serialize(value).map_err(|e| Error::codec(Self::FORMAT, e))
+    }
+
+    fn decode<'de, T: Deserialize<'de>>(data: &'de [u8]) -> Result<T> {
+        bincode::deserialize(data).map_err(|e| Error::codec(Self::FORMAT, e))
+    }
+}
+
+#[cfg(feature = "std")]
+impl Codec for Json {
+    const FORMAT: Format = Format::Json;
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(|e| Error::codec(Self::FORMAT, e))
+    }
+
+    fn decode<'de, T: Deserialize<'de>>(data: &'de [u8]) -> Result<T> {
+        serde_json::from_slice(data).map_err(|e| Error::codec(Self::FORMAT, e))
+    }
+}
+
+#[cfg(feature = "std")]
+impl Codec for MessagePack {
+    const FORMAT: Format = Format::MessagePack;
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        rmp_serde::to_vec_named(value).map_err(|e| Error::codec(Self::FORMAT, e))
+    }
+
+    fn decode<'de, T: Deserialize<'de>>(data: &'de [u8]) -> Result<T> {
+        rmp_serde::from_slice(data).map_err(|e| Error::codec(Self::FORMAT, e))
+    }
+}
+
+#[cfg(feature = "std")]
+impl Codec for Cbor {
+    const FORMAT: Format = Format::Cbor;
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        serde_cbor::to_vec(value).map_err(|e| Error::codec(Self::FORMAT, e))
+    }
+
+    fn decode<'de, T: Deserialize<'de>>(data: &'de [u8]) -> Result<T> {
+        serde_cbor::from_slice(data).map_err(|e| Error::codec(Self::FORMAT, e))
+    }
+}
+
+/// Format of a payload, passed across the ABI as its `tag`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Bincode,
+    Json,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+    Cbor,
+}
+
+impl Format {
+    pub fn tag(self) -> u32 {
+        match self {
+            Format::Bincode => 0,
+            Format::Json => 1,
+            Format::MessagePack => 2,
+            Format::Cbor => 3,
+        }
+    }
+
+    pub fn from_tag(tag: u32) -> Result<Self> {
+        match tag {
+            0 => Ok(Format::Bincode),
+            1 => Ok(Format::Json),
+            2 => Ok(Format::MessagePack),
+            3 => Ok(Format::Cbor),
+            _ => Err(Error::UnknownFormat(tag)),
+        }
+    }
+
+    /// Media type of payloads in this format.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Bincode => "application/octet-stream",
+            Format::Json => "application/json",
+            Format::MessagePack => "application/msgpack",
+            Format::Cbor => "application/cbor",
+        }
+    }
+
+    /// The format of a media type, its parameters are ignored.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        let formats = [
+            ("application/octet-stream", Format::Bincode),
+            ("application/x-bincode", Format::Bincode),
+            ("application/json", Format::Json),
+            ("application/msgpack", Format::MessagePack),
+            ("application/x-msgpack", Format::MessagePack),
+            ("application/vnd.msgpack", Format::MessagePack),
+            ("application/cbor", Format::Cbor),
+        ];
+        formats.iter().find(|(name, _)| name.eq_ignore_ascii_case(mime)).map(|(_, format)| *format)
+    }
+
+    #[cfg(feature = "std")]
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            Format::Bincode => Bincode::encode(value),
+            Format::Json => Json::encode(value),
+            Format::MessagePack => MessagePack::encode(value),
+            Format::Cbor => Cbor::encode(value),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn decode<'de, T: Deserialize<'de>>(self, data: &'de [u8]) -> Result<T> {
+        match self {
+            Format::Bincode => Bincode::decode(data),
+            Format::Json => Json::decode(data),
+            Format::MessagePack => MessagePack::decode(data),
+            Format::Cbor => Cbor::decode(data),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Format::Bincode => "bincode",
+            Format::Json => "json",
+            Format::MessagePack => "msgpack",
+            Format::Cbor => "cbor",
+        })
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> core::result::Result<Self, Self::Err> {
+        match s {
+            "bincode" => Ok(Format::Bincode),
+            "json" => Ok(Format::Json),
+            "msgpack" | "messagepack" => Ok(Format::MessagePack),
+            "cbor" => Ok(Format::Cbor),
+            _ => Err(format!("unknown format {}, expected bincode, json, msgpack or cbor", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::collections::BTreeMap;
+    use alloc::vec;
+
+    use super::*;
+
+    const FORMATS: [Format; 4] = [Format::Bincode, Format::Json, Format::MessagePack, Format::Cbor];
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Value {
+        id: u64,
+        name: String,
+        tags: Vec<String>,
+        scores: BTreeMap<String, i32>,
+        parent: Option<Box<Value>>,
+        kind: Kind,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Kind {
+        Unit,
+        Tuple(i8, bool),
+        Struct { bytes: Vec<u8> },
+    }
+
+    fn value() -> Value {
+        let leaf = Value {
+            id: 1,
+            name: "leaf".to_string(),
+            tags: vec![],
+            scores: BTreeMap::new(),
+            parent: None,
+            kind: Kind::Unit,
+        };
+        Value {
+            id: u64::MAX,
+            name: "ünïcödé".to_string(),
+            tags: vec!["a".to_string(), "b".to_string()],
+            scores: vec![("x".to_string(), -1), ("y".to_string(), i32::MAX)].into_iter().collect(),
+            parent: Some(Box::new(leaf)),
+            kind: Kind::Struct { bytes: vec![0, 255] },
+        }
+    }
+
+    #[test]
+    fn round_trips_in_every_format() {
+        for format in FORMATS.iter() {
+            let encoded = format.encode(&value()).unwrap();
+            assert_eq!(format.decode::<Value>(&encoded).unwrap(), value(), "{}", format);
+
+            let encoded = format.encode(&(Kind::Tuple(-3, true), ())).unwrap();
+            assert_eq!(format.decode::<(Kind, ())>(&encoded).unwrap(), (Kind::Tuple(-3, true), ()), "{}", format);
+        }
+    }
+
+    #[test]
+    fn codecs_agree_with_formats() {
+        assert_eq!(Bincode::encode(&value()).unwrap(), Format::Bincode.encode(&value()).unwrap());
+        assert_eq!(Json::encode(&value()).unwrap(), Format::Json.encode(&value()).unwrap());
+        assert_eq!(MessagePack::encode(&value()).unwrap(), Format::MessagePack.encode(&value()).unwrap());
+        assert_eq!(Cbor::encode(&value()).unwrap(), Format::Cbor.encode(&value()).unwrap());
+    }
+
+    #[test]
+    fn invalid_payloads_are_codec_errors() {
+        for format in FORMATS.iter() {
+            match format.decode::<Value>(&[0xc1, 0xff]) {
+                Err(Error::Codec { format: failed, .. }) => assert_eq!(failed, *format),
+                other => panic!("{}: {:?}", format, other),
+            }
+        }
+    }
+
+    #[test]
+    fn tags_round_trip() {
+        for format in FORMATS.iter() {
+            assert_eq!(Format::from_tag(format.tag()).unwrap(), *format);
+            assert_eq!(format.to_string().parse::<Format>().unwrap(), *format);
+        }
+        assert!(matches!(Format::from_tag(4), Err(Error::UnknownFormat(4))));
+        assert_eq!(Format::default(), Format::Bincode);
+    }
+
+    #[test]
+    fn content_types() {
+        for format in FORMATS.iter() {
+            assert_eq!(Format::from_content_type(format.content_type()), Some(*format));
+        }
+        assert_eq!(Format::from_content_type("Application/JSON; charset=utf-8"), Some(Format::Json));
+        assert_eq!(Format::from_content_type("application/x-msgpack"), Some(Format::MessagePack));
+        assert_eq!(Format::from_content_type("text/plain"), None);
+    }
+}
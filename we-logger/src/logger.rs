@@ -1,17 +1,24 @@
+use we_proto::Format;
+
 use crate::Record;
 
+/// Format records are sent in, the host decodes any.
+const FORMAT: Format = Format::Bincode;
+
 #[link(wasm_import_module = "__wasm_everything_runtime__")]
 extern "C" {
-    fn log_proxy(record_ptr: *const u8, record_len: usize);
+    fn log_proxy(record_ptr: *const u8, record_len: usize, format: u32);
 }
 
 struct Logger;
 
 pub static LOGGER: &dyn log::Log = &Logger;
 
+/// Install the logger, does nothing if a logger is already installed.
 pub fn init() {
-    log::set_logger(LOGGER).unwrap();
-    log::set_max_level(log::LevelFilter::Trace);
+    if log::set_logger(LOGGER).is_ok() {
+        log::set_max_level(log::LevelFilter::Trace);
+    }
 }
 
 impl log::Log for Logger {
@@ -21,9 +28,9 @@ impl log::Log for Logger {
 
     fn log(&self, record: &log::Record) {
         let record: Record = record.into();
-        let serialized = bincode::serialize(&record).unwrap(); // should never fail
+        let serialized = FORMAT.encode(&record).unwrap(); // should never fail
         unsafe {
-            log_proxy(serialized.as_ptr(), serialized.len())
+            log_proxy(serialized.as_ptr(), serialized.len(), FORMAT.tag())
         }
     }
 
@@ -0,0 +1,61 @@
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{FnArg, ItemFn, Result};
+
+pub fn expand(mut item: ItemFn) -> Result<TokenStream> {
+    if !item.sig.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(&item.sig.generics, "exports cannot be generic"));
+    }
+
+    let mut arg_types = Vec::new();
+    for arg in item.sig.inputs.iter() {
+        match arg {
+            FnArg::Typed(arg) => arg_types.push((*arg.ty).clone()),
+            FnArg::Receiver(receiver) => {
+                return Err(syn::Error::new_spanned(receiver, "exports cannot take `self`"))
+            }
+        }
+    }
+    let arg_idents = (0..arg_types.len())
+        .map(|i| format_ident!("__arg{}", i))
+        .collect::<Vec<_>>();
+
+    let vis = item.vis.clone();
+    let export_ident = item.sig.ident.clone();
+    let inner_ident = format_ident!("__we_export_{}", export_ident);
+    item.sig.ident = inner_ident.clone();
+    item.vis = syn::Visibility::Inherited;
+
+    let call = if item.sig.asyncness.is_some() {
+        quote!(#inner_ident(#(#arg_idents),*).await)
+    } else {
+        quote!(#inner_ident(#(#arg_idents),*))
+    };
+
+    let (params, decode) = if arg_types.is_empty() {
+        (quote!(request_id: u64), quote!())
+    } else {
+        (
+            quote!(args_ptr: *const u8, args_len: usize, request_id: u64),
+            quote! {
+                let export = stringify!(#export_ident);
+                let (#(#arg_idents,)*): (#(#arg_types,)*) = match ::we_rt::export::decode_args(export, format, args_ptr, args_len, request_id) {
+                    Some(args) => args,
+                    None => return,
+                };
+            },
+        )
+    };
+
+    Ok(quote! {
+        #[no_mangle]
+        #vis unsafe extern "C" fn #export_ident(#params) {
+            #item
+
+            ::we_rt::init_logger();
+            let format = ::we_rt::export::take_format();
+            #decode
+            ::we_rt::export::spawn(request_id, format, async move { #call });
+        }
+    })
+}
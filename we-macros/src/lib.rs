@@ -0,0 +1,43 @@
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use syn::{parse_macro_input, AttributeArgs, Item};
+
+mod export;
+mod service;
+
+/// Define a typed service.
+///
+/// On a trait it generates a `<Trait>Client` stub calling the service through `we_rt::invoke`,
+/// and a `<Trait>Server` wrapper dispatching calls to an implementation, which is registered with
+/// `we_rt::serve`. The service name defaults to the trait name and can be set with
+/// `#[we_rt::service(name = "...")]`.
+///
+/// On an impl block of such a trait it allows `async fn` methods.
+#[proc_macro_attribute]
+pub fn service(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as AttributeArgs);
+    let expanded = match parse_macro_input!(item as Item) {
+        Item::Trait(item) => service::expand_trait(args, item),
+        Item::Impl(item) => service::expand_impl(item),
+        item => Err(syn::Error::new_spanned(item, "#[service] expects a trait or an impl block")),
+    };
+    expanded.unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+/// Export a function to the host.
+///
+/// Generates the `#[no_mangle]` shim which installs the logger, decodes the arguments, runs the
+/// function on a new `we_rt::Runtime` and replies its serialized return value to the host.
+/// The shim takes `(request_id)` when the function has no arguments and
+/// `(args_ptr, args_len, request_id)` otherwise, with the arguments encoded as a tuple.
+#[proc_macro_attribute]
+pub fn export(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return syn::Error::new(proc_macro2::Span::call_site(), "#[export] takes no arguments")
+            .to_compile_error()
+            .into();
+    }
+    let item = parse_macro_input!(item as syn::ItemFn);
+    export::expand(item).unwrap_or_else(|e| e.to_compile_error()).into()
+}
@@ -0,0 +1,204 @@
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{
+    parse_quote, AttributeArgs, Block, FnArg, ImplItem, ItemImpl, ItemTrait, Lit, Meta, NestedMeta,
+    Result, ReturnType, Signature, TraitItem, Type,
+};
+
+struct Method {
+    ident: syn::Ident,
+    is_async: bool,
+    args: Vec<Type>,
+    output: Type,
+}
+
+pub fn expand_trait(args: AttributeArgs, mut item: ItemTrait) -> Result<TokenStream> {
+    if !item.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(&item.generics, "services cannot be generic"));
+    }
+    let name = service_name(args)?.unwrap_or_else(|| item.ident.to_string());
+
+    let mut methods = Vec::new();
+    for trait_item in item.items.iter_mut() {
+        if let TraitItem::Method(method) = trait_item {
+            methods.push(parse_method(&method.sig)?);
+            if method.sig.asyncness.is_some() {
+                box_async(&mut method.sig);
+                if let Some(block) = method.default.as_mut() {
+                    box_block(block);
+                }
+            }
+        }
+    }
+
+    let vis = &item.vis;
+    let trait_ident = &item.ident;
+    let client = format_ident!("{}Client", trait_ident);
+    let server = format_ident!("{}Server", trait_ident);
+
+    let client_methods = methods.iter().map(|method| {
+        let ident = &method.ident;
+        let method_name = ident.to_string();
+        let arg_idents = arg_idents(method);
+        let arg_types = &method.args;
+        let output = &method.output;
+        quote! {
+            pub fn #ident(&self, #(#arg_idents: #arg_types),*) -> ::we_rt::AsyncResult<::we_rt::Result<#output>> {
+                ::we_rt::invoke_with(self.format, self.name, #method_name, (#(#arg_idents,)*))
+            }
+        }
+    });
+
+    let dispatch_arms = methods.iter().map(|method| {
+        let ident = &method.ident;
+        let method_name = ident.to_string();
+        let arg_idents = arg_idents(method);
+        let arg_types = &method.args;
+        let call = if method.is_async {
+            quote!(self.inner.#ident(#(#arg_idents),*).await)
+        } else {
+            quote!(self.inner.#ident(#(#arg_idents),*))
+        };
+        quote! {
+            #method_name => {
+                let (#(#arg_idents,)*): (#(#arg_types,)*) = match ::we_rt::service::decode(format, args) {
+                    Ok(args) => args,
+                    Err(e) => return Some(::we_rt::service::boxed(async move { Err(e) })),
+                };
+                Some(::we_rt::service::boxed(async move { ::we_rt::service::encode(format, &#call) }))
+            }
+        }
+    });
+
+    Ok(quote! {
+        #item
+
+        #[derive(Clone, Copy, Debug)]
+        #vis struct #client {
+            name: &'static str,
+            format: ::we_rt::Format,
+        }
+
+        impl #client {
+            pub const NAME: &'static str = #name;
+
+            pub fn new() -> Self {
+                Self::with_name(Self::NAME)
+            }
+
+            pub fn with_name(name: &'static str) -> Self {
+                Self { name, format: ::we_rt::Format::Bincode }
+            }
+
+            /// Send arguments and get replies in `format` instead of bincode.
+            pub fn with_format(mut self, format: ::we_rt::Format) -> Self {
+                self.format = format;
+                self
+            }
+
+            #(#client_methods)*
+        }
+
+        impl Default for #client {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        #vis struct #server<S> {
+            name: &'static str,
+            inner: S,
+        }
+
+        impl<S: #trait_ident + 'static> #server<S> {
+            pub fn new(inner: S) -> Self {
+                Self { name: #client::NAME, inner }
+            }
+
+            pub fn with_name(name: &'static str, inner: S) -> Self {
+                Self { name, inner }
+            }
+        }
+
+        impl<S: #trait_ident + 'static> ::we_rt::service::Service for #server<S> {
+            fn name(&self) -> &str {
+                self.name
+            }
+
+            fn dispatch(&'static self, method: &str, format: ::we_rt::Format, args: &[u8]) -> Option<::we_rt::service::DispatchFuture> {
+                match method {
+                    #(#dispatch_arms)*
+                    _ => None,
+                }
+            }
+        }
+    })
+}
+
+pub fn expand_impl(mut item: ItemImpl) -> Result<TokenStream> {
+    for impl_item in item.items.iter_mut() {
+        if let ImplItem::Method(method) = impl_item {
+            if method.sig.asyncness.is_some() {
+                box_async(&mut method.sig);
+                box_block(&mut method.block);
+            }
+        }
+    }
+    Ok(quote!(#item))
+}
+
+fn service_name(args: AttributeArgs) -> Result<Option<String>> {
+    let mut name = None;
+    for arg in args {
+        match arg {
+            NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("name") => match nv.lit {
+                Lit::Str(lit) => name = Some(lit.value()),
+                lit => return Err(syn::Error::new_spanned(lit, "expected a string")),
+            },
+            arg => return Err(syn::Error::new_spanned(arg, "unknown argument, expected `name = \"...\"`")),
+        }
+    }
+    Ok(name)
+}
+
+fn parse_method(sig: &Signature) -> Result<Method> {
+    let mut inputs = sig.inputs.iter();
+    match inputs.next() {
+        Some(FnArg::Receiver(receiver)) if receiver.reference.is_some() && receiver.mutability.is_none() => {}
+        _ => return Err(syn::Error::new_spanned(sig, "service methods must take `&self`")),
+    }
+    let args = inputs
+        .map(|arg| match arg {
+            FnArg::Typed(arg) => Ok((*arg.ty).clone()),
+            FnArg::Receiver(receiver) => Err(syn::Error::new_spanned(receiver, "unexpected receiver")),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let output = match &sig.output {
+        ReturnType::Default => parse_quote!(()),
+        ReturnType::Type(_, ty) => (**ty).clone(),
+    };
+    Ok(Method {
+        ident: sig.ident.clone(),
+        is_async: sig.asyncness.is_some(),
+        args,
+        output,
+    })
+}
+
+fn arg_idents(method: &Method) -> Vec<syn::Ident> {
+    (0..method.args.len()).map(|i| format_ident!("__arg{}", i)).collect()
+}
+
+/// `async fn f(..) -> T` to `fn f(..) -> LocalBoxFuture<'_, T>`
+fn box_async(sig: &mut Signature) {
+    let output = match &sig.output {
+        ReturnType::Default => parse_quote!(()),
+        ReturnType::Type(_, ty) => (**ty).clone(),
+    };
+    sig.asyncness = None;
+    sig.output = parse_quote!(-> ::we_rt::service::LocalBoxFuture<'_, #output>);
+}
+
+fn box_block(block: &mut Block) {
+    *block = parse_quote!({ ::we_rt::service::boxed(async move #block) });
+}
@@ -0,0 +1,131 @@
+//! Descriptor and function signatures of the ABI, the host checks guests against them before instantiating.
+
+/// Version of the host/guest ABI, bumped on any incompatible change to imports, exports or envelopes.
+pub const ABI_VERSION: u32 = 3;
+
+/// The module serves services through `_wasm_dispatch`.
+pub const FEATURE_DISPATCH: u32 = 1 << 0;
+
+/// In-flight host requests can be cancelled through `_wasm_cancel`.
+pub const FEATURE_CANCEL: u32 = 1 << 1;
+
+/// The module is built for `wasm32-wasi`, the host must provide WASI imports.
+pub const FEATURE_WASI: u32 = 1 << 2;
+
+/// Exports accept arguments and reply in the format the host selects through `_wasm_set_format`.
+pub const FEATURE_FORMATS: u32 = 1 << 3;
+
+/// Custom section holding the `(version, features)` descriptor of a guest.
+pub const ABI_SECTION: &str = "we_abi";
+
+/// Module the host functions are imported from.
+pub const IMPORT_MODULE: &str = "__wasm_everything_runtime__";
+
+/// Import modules of the WASI versions the host provides.
+pub const WASI_MODULES: &[&str] = &["wasi_snapshot_preview1", "wasi_unstable"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValType {
+    I32,
+    I64,
+}
+
+use ValType::*;
+
+/// A function of the ABI, pointers and lengths are `I32`, request ids `I64`.
+#[derive(Clone, Copy, Debug)]
+pub struct Signature {
+    pub name: &'static str,
+    pub params: &'static [ValType],
+    pub results: &'static [ValType],
+}
+
+const fn signature(name: &'static str, params: &'static [ValType], results: &'static [ValType]) -> Signature {
+    Signature { name, params, results }
+}
+
+/// Host functions guests may import from `IMPORT_MODULE`.
+pub const IMPORTS: &[Signature] = &[
+    // `Request` envelope, see `envelope`
+    signature("invoke", &[I32, I32, I64], &[]),
+    // `Response` envelope, see `envelope`
+    signature("callback", &[I64, I32, I32], &[]),
+    // `Record` in the format of the last parameter
+    signature("log_proxy", &[I32, I32, I32], &[]),
+    signature("config", &[I32, I32, I32], &[I32]),
+    signature("now", &[], &[I64]),
+    signature("sleep", &[I64, I64], &[]),
+    signature("kv_get", &[I32, I32, I64], &[]),
+    signature("kv_put", &[I32, I32, I32, I32, I64], &[]),
+    signature("kv_delete", &[I32, I32, I64], &[]),
+    signature("kv_scan_prefix", &[I32, I32, I64], &[]),
+    signature("http_fetch", &[I32, I32, I64], &[]),
+];
+
+/// Functions every guest exports.
+pub const EXPORTS: &[Signature] = &[
+    signature("set_instance_id", &[I64], &[I32]),
+    signature("get_instance_id", &[], &[I64]),
+    signature("_wasm_malloc", &[I32], &[I32]),
+    signature("_wasm_free", &[I32, I32], &[]),
+    // `Response` envelope, see `envelope`
+    signature("_wasm_resolve", &[I64, I32, I32], &[I32]),
+];
+
+/// Functions guests may export, checked if present.
+pub const OPTIONAL_EXPORTS: &[Signature] = &[signature("init", &[], &[]), signature("_initialize", &[], &[])];
+
+/// Exported with `FEATURE_DISPATCH`, takes a `Request` envelope.
+pub const DISPATCH: Signature = signature("_wasm_dispatch", &[I32, I32, I64], &[I32]);
+
+/// Exported with `FEATURE_CANCEL`.
+pub const CANCEL: Signature = signature("_wasm_cancel", &[I64], &[I32]);
+
+/// Exported with `FEATURE_FORMATS`, takes a `Format` tag.
+pub const SET_FORMAT: Signature = signature("_wasm_set_format", &[I32], &[I32]);
+
+/// Parameters of an export taking arguments, `(args_ptr, args_len, request_id)`.
+pub const EXPORT_WITH_ARGS: &[ValType] = &[I32, I32, I64];
+
+/// Parameters of an export taking no arguments, `(request_id)`.
+pub const EXPORT_WITHOUT_ARGS: &[ValType] = &[I64];
+
+/// `(version, features)` as little endian `u32`s, the content of the `we_abi` section.
+pub const fn descriptor(features: u32) -> [u8; 8] {
+    let version = ABI_VERSION.to_le_bytes();
+    let features = features.to_le_bytes();
+    [
+        version[0], version[1], version[2], version[3],
+        features[0], features[1], features[2], features[3],
+    ]
+}
+
+/// `(version, features)` of a `we_abi` section, `None` if it is malformed.
+pub fn parse_descriptor(section: &[u8]) -> Option<(u32, u32)> {
+    if section.len() != 8 {
+        return None;
+    }
+    let mut version = [0u8; 4];
+    let mut features = [0u8; 4];
+    version.copy_from_slice(&section[..4]);
+    features.copy_from_slice(&section[4..]);
+    Some((u32::from_le_bytes(version), u32::from_le_bytes(features)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn descriptors_round_trip() {
+        let features = FEATURE_DISPATCH | FEATURE_FORMATS;
+        assert_eq!(parse_descriptor(&descriptor(features)), Some((ABI_VERSION, features)));
+    }
+
+    #[test]
+    fn malformed_descriptors_are_refused() {
+        assert_eq!(parse_descriptor(&[]), None);
+        assert_eq!(parse_descriptor(&descriptor(0)[..7]), None);
+        assert_eq!(parse_descriptor(&[0; 9]), None);
+    }
+}
@@ -0,0 +1,277 @@
+//! Envelopes framing calls and replies, in the same layout whatever the format of the payload they carry.
+//!
+//! The layout is part of the ABI, guests in any language encode and decode it by hand:
+//!
+//! - integers are little endian, lengths are `u64` followed by as many bytes, strings are UTF-8
+//! - `Request`: `service` and `method` as strings, `format` as its `u32` tag, then `args`
+//! - `Response`: a `u32` variant, then for `Ok` (0) the payload, for `Err` (1) the `u32` `ErrorCode`
+//!   and the message as a string
+//!
+//! Nothing follows the last field. This is also how bincode 1.x encodes these types with its default options,
+//! so Rust guests may use either.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+use serde::{Deserialize, Serialize};
+use we_codec::{Error, Format, Result};
+
+use crate::error::ErrorCode;
+
+/// A call of `method` on `service`, passed to the `invoke` import and the `_wasm_dispatch` export.
+#[derive(Debug)]
+pub struct Request<'a> {
+    pub service: &'a str,
+    pub method: &'a str,
+    /// Format of `args` and of the reply payload
+    pub format: Format,
+    pub args: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(28 + self.service.len() + self.method.len() + self.args.len());
+        put_bytes(&mut data, self.service.as_bytes());
+        put_bytes(&mut data, self.method.as_bytes());
+        data.extend_from_slice(&self.format.tag().to_le_bytes());
+        put_bytes(&mut data, self.args);
+        data
+    }
+
+    pub fn decode(data: &'a [u8]) -> Result<Self> {
+        let mut reader = Reader(data);
+        let request = Request {
+            service: reader.str()?,
+            method: reader.str()?,
+            format: Format::from_tag(reader.u32()?)?,
+            args: reader.bytes()?,
+        };
+        reader.end()?;
+        Ok(request)
+    }
+}
+
+/// Reply to a call, passed to the `callback` import and the `_wasm_resolve` export.
+#[derive(Debug)]
+pub enum Response<'a> {
+    /// The reply payload, in the format of the call
+    Ok(&'a [u8]),
+    Err(RemoteError),
+}
+
+impl<'a> Response<'a> {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        match self {
+            Response::Ok(payload) => {
+                data.extend_from_slice(&0u32.to_le_bytes());
+                put_bytes(&mut data, payload);
+            }
+            Response::Err(e) => {
+                data.extend_from_slice(&1u32.to_le_bytes());
+                data.extend_from_slice(&(e.code as u32).to_le_bytes());
+                put_bytes(&mut data, e.message.as_bytes());
+            }
+        }
+        data
+    }
+
+    pub fn decode(data: &'a [u8]) -> Result<Self> {
+        let mut reader = Reader(data);
+        let response = match reader.u32()? {
+            0 => Response::Ok(reader.bytes()?),
+            1 => Response::Err(RemoteError {
+                code: ErrorCode::from_u32(reader.u32()?),
+                message: reader.str()?.to_string(),
+            }),
+            variant => return Err(invalid(&alloc::format!("unknown response variant {}", variant))),
+        };
+        reader.end()?;
+        Ok(response)
+    }
+
+    pub fn status(&self) -> ErrorCode {
+        match self {
+            Response::Ok(_) => ErrorCode::Ok,
+            Response::Err(e) => e.code,
+        }
+    }
+}
+
+/// An error crossing the host/guest boundary.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RemoteError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+fn put_bytes(data: &mut Vec<u8>, bytes: &[u8]) {
+    data.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+    data.extend_from_slice(bytes);
+}
+
+/// Reads the fields of an envelope in order.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(invalid("truncated envelope"));
+        }
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = usize::try_from(self.u64()?).map_err(|_| invalid("truncated envelope"))?;
+        self.take(len)
+    }
+
+    fn str(&mut self) -> Result<&'a str> {
+        core::str::from_utf8(self.bytes()?).map_err(|e| invalid(&e.to_string()))
+    }
+
+    fn end(self) -> Result<()> {
+        match self.0.len() {
+            0 => Ok(()),
+            len => Err(invalid(&alloc::format!("{} bytes after the envelope", len))),
+        }
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::Codec { format: Format::Bincode, message: message.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn requests_round_trip() {
+        let args = vec![1, 2, 3];
+        let request = Request { service: "hello", method: "add_one", format: Format::Cbor, args: &args };
+        let encoded = request.encode();
+        let decoded = Request::decode(&encoded).unwrap();
+        assert_eq!(
+            (decoded.service, decoded.method, decoded.format, decoded.args),
+            ("hello", "add_one", Format::Cbor, &args[..])
+        );
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        let encoded = Response::Ok(b"reply").encode();
+        match Response::decode(&encoded).unwrap() {
+            response @ Response::Ok(b"reply") => assert_eq!(response.status(), ErrorCode::Ok),
+            response => panic!("{:?}", response),
+        }
+
+        let error = RemoteError { code: ErrorCode::Timeout, message: "call timed out".to_string() };
+        let encoded = Response::Err(error.clone()).encode();
+        match Response::decode(&encoded).unwrap() {
+            Response::Err(decoded) => assert_eq!(decoded, error),
+            response => panic!("{:?}", response),
+        }
+    }
+
+    /// Guests and hosts built apart must agree on the bytes, not only on the types.
+    #[test]
+    fn encoding_is_stable() {
+        let request = Request { service: "s", method: "m", format: Format::Json, args: &[7] };
+        let expected = [
+            [1, 0, 0, 0, 0, 0, 0, 0, b's'].as_ref(),
+            &[1, 0, 0, 0, 0, 0, 0, 0, b'm'],
+            &[1, 0, 0, 0],
+            &[1, 0, 0, 0, 0, 0, 0, 0, 7],
+        ];
+        assert_eq!(request.encode(), expected.concat());
+
+        let error = RemoteError { code: ErrorCode::Codec, message: "e".to_string() };
+        assert_eq!(Response::Err(error).encode(), [1, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, b'e']);
+    }
+
+    #[test]
+    fn hand_built_envelopes_decode() {
+        let request = [
+            [5, 0, 0, 0, 0, 0, 0, 0].as_ref(),
+            b"hello",
+            &[7, 0, 0, 0, 0, 0, 0, 0],
+            b"add_one",
+            &[3, 0, 0, 0],
+            &[2, 0, 0, 0, 0, 0, 0, 0, 0xc1, 0x01],
+        ]
+        .concat();
+        let decoded = Request::decode(&request).unwrap();
+        assert_eq!(
+            (decoded.service, decoded.method, decoded.format, decoded.args),
+            ("hello", "add_one", Format::Cbor, &[0xc1, 0x01][..])
+        );
+
+        let response = [0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, b'o', b'k'];
+        assert!(matches!(Response::decode(&response).unwrap(), Response::Ok(b"ok")));
+        let response = [1, 0, 0, 0, 16, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, b'n', b'o'];
+        match Response::decode(&response).unwrap() {
+            Response::Err(e) => assert_eq!((e.code, e.message.as_str()), (ErrorCode::Timeout, "no")),
+            response => panic!("{:?}", response),
+        }
+    }
+
+    /// Rust guests may encode envelopes with bincode instead.
+    #[test]
+    fn bincode_agrees_with_the_layout() {
+        #[derive(Serialize)]
+        enum BincodeResponse<'a> {
+            Ok(&'a [u8]),
+            Err(RemoteError),
+        }
+
+        let request = Request { service: "hello", method: "add_one", format: Format::MessagePack, args: &[1, 2] };
+        let fields = (request.service, request.method, request.format, request.args);
+        assert_eq!(bincode::serialize(&fields).unwrap(), request.encode());
+
+        assert_eq!(bincode::serialize(&BincodeResponse::Ok(b"ok")).unwrap(), Response::Ok(b"ok").encode());
+        let error = RemoteError { code: ErrorCode::Busy, message: "busy".to_string() };
+        assert_eq!(bincode::serialize(&BincodeResponse::Err(error.clone())).unwrap(), Response::Err(error).encode());
+    }
+
+    #[test]
+    fn unknown_error_codes_decode_as_unknown() {
+        let encoded = Response::Err(RemoteError { code: ErrorCode::Http, message: "".to_string() }).encode();
+        let mut newer = encoded.clone();
+        newer[4] = 200;
+        match Response::decode(&newer).unwrap() {
+            Response::Err(e) => assert_eq!(e.code, ErrorCode::Unknown),
+            response => panic!("{:?}", response),
+        }
+    }
+
+    #[test]
+    fn truncated_envelopes_are_errors() {
+        let encoded = Request { service: "hello", method: "m", format: Format::Bincode, args: &[] }.encode();
+        assert!(Request::decode(&encoded[..encoded.len() - 1]).is_err());
+        assert!(Response::decode(&[]).is_err());
+
+        let mut trailing = encoded.clone();
+        trailing.push(0);
+        assert!(Request::decode(&trailing).is_err());
+        assert!(Response::decode(&[2, 0, 0, 0]).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use core::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Status of a reply, `Ok` or the kind of error it carries. Encoded as its `u32` value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    Ok = 0,
+    /// A code this version does not know, sent by a newer host or guest
+    Unknown = 1,
+    /// A value failed to encode or decode
+    Codec = 2,
+    Runtime = 3,
+    Export = 4,
+    Compile = 5,
+    Instantiation = 6,
+    Abi = 7,
+    ServiceNotFound = 8,
+    OutOfBounds = 9,
+    OutOfMemory = 10,
+    Utf8 = 11,
+    UnknownRequest = 12,
+    OutOfFuel = 13,
+    InstanceNotFound = 14,
+    Terminated = 15,
+    Timeout = 16,
+    Cancelled = 17,
+    LogChannelClosed = 18,
+    Wasi = 19,
+    Kv = 20,
+    Http = 21,
+    HttpDenied = 22,
+    HttpBodyTooLarge = 23,
+    /// Every instance of the service stayed busy
+    Busy = 24,
+}
+
+use ErrorCode::*;
+
+const CODES: &[ErrorCode] = &[
+    Ok, Unknown, Codec, Runtime, Export, Compile, Instantiation, Abi, ServiceNotFound, OutOfBounds, OutOfMemory,
+    Utf8, UnknownRequest, OutOfFuel, InstanceNotFound, Terminated, Timeout, Cancelled, LogChannelClosed, Wasi, Kv,
+    Http, HttpDenied, HttpBodyTooLarge, Busy,
+];
+
+impl ErrorCode {
+    pub fn from_u32(code: u32) -> Self {
+        CODES.get(code as usize).copied().unwrap_or(Unknown)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Ok => "Ok",
+            Unknown => "Unknown",
+            Codec => "Codec",
+            Runtime => "Runtime",
+            Export => "Export",
+            Compile => "Compile",
+            Instantiation => "Instantiation",
+            Abi => "Abi",
+            ServiceNotFound => "ServiceNotFound",
+            OutOfBounds => "OutOfBounds",
+            OutOfMemory => "OutOfMemory",
+            Utf8 => "Utf8",
+            UnknownRequest => "UnknownRequest",
+            OutOfFuel => "OutOfFuel",
+            InstanceNotFound => "InstanceNotFound",
+            Terminated => "Terminated",
+            Timeout => "Timeout",
+            Cancelled => "Cancelled",
+            LogChannelClosed => "LogChannelClosed",
+            Wasi => "Wasi",
+            Kv => "Kv",
+            Http => "Http",
+            HttpDenied => "HttpDenied",
+            HttpBodyTooLarge => "HttpBodyTooLarge",
+            Busy => "Busy",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(*self as u32)
+    }
+}
+
+impl<'de> Deserialize<'de> for ErrorCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u32::deserialize(deserializer).map(ErrorCode::from_u32)
+    }
+}
@@ -0,0 +1,15 @@
+//! The host/guest wire protocol: ABI descriptor, function signatures, envelopes, error codes and log records.
+//!
+//! Host and guests are built against this crate, any incompatible change to it bumps `abi::ABI_VERSION`.
+#![no_std]
+extern crate alloc;
+
+pub mod abi;
+pub mod envelope;
+pub mod error;
+pub mod record;
+
+pub use we_codec::Format;
+
+pub use crate::envelope::{RemoteError, Request, Response};
+pub use crate::error::ErrorCode;
@@ -0,0 +1,16 @@
+//! Descriptor the host reads before instantiating a module, see `ABI_VERSION`.
+
+pub use we_proto::abi::{ABI_VERSION, FEATURE_CANCEL, FEATURE_DISPATCH, FEATURE_FORMATS, FEATURE_WASI};
+
+#[cfg(not(target_os = "wasi"))]
+pub const FEATURES: u32 = FEATURE_DISPATCH | FEATURE_CANCEL | FEATURE_FORMATS;
+#[cfg(target_os = "wasi")]
+pub const FEATURES: u32 = FEATURE_DISPATCH | FEATURE_CANCEL | FEATURE_FORMATS | FEATURE_WASI;
+
+/// `(version, features)` as little endian `u32`s, in the `we_abi` custom section.
+///
+/// The linker only keeps the section if the object holding it is linked, `set_instance_id` refers to it.
+#[cfg(target_arch = "wasm32")]
+#[used]
+#[link_section = "we_abi"]
+pub(crate) static WE_ABI: [u8; 8] = we_proto::abi::descriptor(FEATURES);
@@ -1,8 +1,40 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use we_proto::{ErrorCode, RemoteError, Response};
+
 use Error::*;
 
 #[derive(Debug)]
 pub enum Error {
-    Bincode(bincode::Error)
+    Bincode(bincode::Error),
+    Codec(we_codec::Error),
+    /// The host could not allocate a reply in guest memory
+    OutOfMemory,
+    /// The host, or the module serving the call, failed with an error of `code`
+    Remote { code: ErrorCode, message: String },
+}
+
+impl Error {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Bincode(_) | Codec(_) => ErrorCode::Codec,
+            OutOfMemory => ErrorCode::OutOfMemory,
+            Remote { code, .. } => *code,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bincode(e) => write!(f, "ser/de error {}", e),
+            Codec(e) => write!(f, "{}", e),
+            OutOfMemory => write!(f, "out of memory"),
+            Remote { code, message } => write!(f, "remote error {}: {}", code, message),
+        }
+    }
 }
 
 impl From<bincode::Error> for Error {
@@ -11,3 +43,26 @@ impl From<bincode::Error> for Error {
     }
 }
 
+impl From<we_codec::Error> for Error {
+    fn from(e: we_codec::Error) -> Self {
+        Codec(e)
+    }
+}
+
+/// Encode the outcome of a call as the reply the host expects on `callback`.
+pub(crate) fn encode_reply(result: Result<&[u8], Error>) -> Vec<u8> {
+    let response = match result {
+        Ok(data) => Response::Ok(data),
+        Err(Remote { code, message }) => Response::Err(RemoteError { code, message }),
+        Err(e) => Response::Err(RemoteError { code: e.code(), message: e.to_string() }),
+    };
+    response.encode()
+}
+
+/// Decode a reply the host passed to `_wasm_resolve`, errors become `Error::Remote`.
+pub(crate) fn decode_reply(data: &[u8]) -> Result<&[u8], Error> {
+    match Response::decode(data)? {
+        Response::Ok(data) => Ok(data),
+        Response::Err(RemoteError { code, message }) => Err(Remote { code, message }),
+    }
+}
@@ -0,0 +1,64 @@
+use core::cell::Cell;
+use core::future::Future;
+
+use we_codec::Format;
+
+use crate::{callback, internal::spawn_request, service::{decode, encode}};
+
+std::thread_local! {
+    /// Format of the export call the host is about to make.
+    static FORMAT: Cell<Format> = const { Cell::new(Format::Bincode) };
+}
+
+/// Called by the host before an export call not using bincode, false if `tag` is unknown, see `Format::tag`.
+#[no_mangle]
+pub extern "C" fn _wasm_set_format(tag: u32) -> bool {
+    match Format::from_tag(tag) {
+        Ok(format) => {
+            FORMAT.with(|call_format| call_format.set(format));
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Format of the export call in progress, the next call defaults to bincode again.
+pub fn take_format() -> Format {
+    FORMAT.with(|format| format.replace(Format::Bincode))
+}
+
+/// Decode the arguments the host passed to an export, failures are logged and replied to `request_id`.
+///
+/// # Safety
+///
+/// `ptr` must point to `len` readable bytes, the arguments the host wrote to guest memory.
+pub unsafe fn decode_args<T: serde::de::DeserializeOwned>(
+    export: &str,
+    format: Format,
+    ptr: *const u8,
+    len: usize,
+    request_id: u64,
+) -> Option<T> {
+    match decode(format, core::slice::from_raw_parts(ptr, len)) {
+        Ok(args) => Some(args),
+        Err(e) => {
+            log::error!("cannot decode arguments of {}: {:?}", export, e);
+            callback(Err(e), request_id);
+            None
+        }
+    }
+}
+
+/// Run an export's future on a new runtime and reply its output to the host, encoded in `format`.
+pub fn spawn<F, T>(request_id: u64, format: Format, future: F)
+where
+    F: Future<Output = T> + 'static,
+    T: serde::Serialize,
+{
+    spawn_request(request_id, async move {
+        match encode(format, &future.await) {
+            Ok(data) => callback(Ok(&data), request_id),
+            Err(e) => callback(Err(e), request_id),
+        }
+    })
+}
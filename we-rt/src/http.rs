@@ -0,0 +1,88 @@
+//! Outbound HTTP through the host, limited to the hosts the deployment allows for the module.
+//!
+//! Requests to other hosts fail with `Error::Remote` of kind `HttpDenied`, responses larger than the
+//! module may receive with `HttpBodyTooLarge`.
+//! Redirects are not followed, they are returned like any other response.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use semi_async::AsyncResult;
+use serde::{Deserialize, Serialize};
+
+use crate::{error, internal, Result};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn new<M: Into<String>, U: Into<String>>(method: M, url: U) -> Self {
+        Self { method: method.into(), url: url.into(), headers: Vec::new(), body: Vec::new() }
+    }
+
+    pub fn get<U: Into<String>>(url: U) -> Self {
+        Self::new("GET", url)
+    }
+
+    pub fn post<U: Into<String>, B: Into<Vec<u8>>>(url: U, body: B) -> Self {
+        Self::new("POST", url).body(body)
+    }
+
+    pub fn header<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> Self {
+        self.body = body.into();
+        self
+    }
+}
+
+impl Response {
+    /// First value of header `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// The body as text, invalid UTF-8 is replaced.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+pub fn fetch(request: Request) -> AsyncResult<Result<Response>> {
+    let request = match bincode::serialize(&request) {
+        Ok(request) => request,
+        Err(e) => return AsyncResult::ready(Err(e.into())),
+    };
+    let result = AsyncResult::default();
+    let inner = result.clone_inner();
+    internal::host_request(
+        |request_id| unsafe { internal::http_fetch(request.as_ptr(), request.len(), request_id) },
+        move |data: &[u8]| {
+            let response = error::decode_reply(data).and_then(|data| bincode::deserialize(data).map_err(|e| e.into()));
+            semi_async::resolve(&inner, response);
+        },
+    );
+    result
+}
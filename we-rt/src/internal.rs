@@ -1,62 +1,125 @@
-use core::ffi::c_void;
-use alloc::vec::Vec;
+use alloc::collections::BTreeMap;
+use core::cell::RefCell;
+use core::future::Future;
 
 use once_cell::sync::OnceCell;
-use semi_async::trampoline;
+use semi_async::wasm_callback::PendingCalls;
+use semi_async::{Runtime, Task};
+use we_proto::{Format, Request};
+
+use crate::error::{encode_reply, Error};
 
 static INSTANCE_ID: OnceCell<u64> = OnceCell::new();
 
-pub type HostCallback = fn(*mut c_void, &[u8]);
+std::thread_local! {
+    static PENDING_CALLS: PendingCalls = PendingCalls::new();
+    /// Tasks serving host requests, by request id.
+    static TASKS: RefCell<BTreeMap<u64, Task>> = const { RefCell::new(BTreeMap::new()) };
+}
 
 #[link(wasm_import_module = "__wasm_everything_runtime__")]
 extern "C" {
-    pub fn invoke(
-        name_ptr: *const u8,
-        name_len: usize,
-        method_ptr: *const u8,
-        method_len: usize,
-        args_ptr: *const u8,
-        args_len: usize,
-        cb: unsafe extern "C" fn(*mut c_void, *const u8, usize),
-        user_data: *mut c_void,
-    );
+    pub fn invoke(request_ptr: *const u8, request_len: usize, request_id: u64);
 
     pub fn callback(
+        request_id: u64,
         ptr: *const u8,
         len: usize,
-        cb: i64, // compiler error
-        user_data: i64,
     );
+
+    pub fn config(
+        key_ptr: *const u8,
+        key_len: usize,
+        ret: *mut usize,
+    ) -> bool;
+
+    pub fn now() -> u64;
+
+    pub fn sleep(nanos: u64, request_id: u64);
+
+    pub fn kv_get(key_ptr: *const u8, key_len: usize, request_id: u64);
+
+    pub fn kv_put(
+        key_ptr: *const u8,
+        key_len: usize,
+        value_ptr: *const u8,
+        value_len: usize,
+        request_id: u64,
+    );
+
+    pub fn kv_delete(key_ptr: *const u8, key_len: usize, request_id: u64);
+
+    pub fn kv_scan_prefix(prefix_ptr: *const u8, prefix_len: usize, request_id: u64);
+
+    pub fn http_fetch(request_ptr: *const u8, request_len: usize, request_id: u64);
 }
 
-pub(crate) fn invoke_callback<F>(name: &[u8], method: &[u8], args: Vec<u8>, mut f: F)
+pub(crate) fn invoke_callback<F>(service: &str, method: &str, format: Format, args: &[u8], f: F)
 where
-    F: FnMut(&[u8]),
+    F: FnOnce(&[u8]) + 'static,
 {
-    let user_data = &mut f as *mut _ as *mut c_void;
+    let request = Request { service, method, format, args }.encode();
+    let request_id = PENDING_CALLS.with(|calls| calls.register(f));
 
     unsafe {
-        invoke(
-            name.as_ptr(), name.len(),
-            method.as_ptr(), method.len(),
-            args.as_ptr(), args.len(),
-            trampoline::<F>, user_data
-        );
+        invoke(request.as_ptr(), request.len(), request_id);
     };
 }
 
+/// Start a host request with `call`, `f` is called with the host's reply.
+pub(crate) fn host_request<C, F>(call: C, f: F)
+where
+    C: FnOnce(u64),
+    F: FnOnce(&[u8]) + 'static,
+{
+    let request_id = PENDING_CALLS.with(|calls| calls.register(f));
+    call(request_id);
+}
+
+/// Serve host request `request_id` with `future`, the host may cancel it through `_wasm_cancel`.
+pub(crate) fn spawn_request<F>(request_id: u64, future: F)
+where
+    F: Future<Output = ()> + 'static,
+{
+    let task = Runtime::new().spawn(async move {
+        future.await;
+        TASKS.with(|tasks| tasks.borrow_mut().remove(&request_id));
+    });
+    if !task.is_done() {
+        TASKS.with(|tasks| tasks.borrow_mut().insert(request_id, task));
+    }
+}
+
+/// Called by the host when it gave up on request `request_id`, drops the task serving it.
+#[no_mangle]
+pub extern "C" fn _wasm_cancel(request_id: u64) -> bool {
+    let task = TASKS.with(|tasks| tasks.borrow_mut().remove(&request_id));
+    match task {
+        Some(task) => {
+            task.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Called by the host to reply to request `request_id`, a null `ptr` if the reply did not fit in memory.
 #[no_mangle]
-pub extern "C" fn call_invoke_callback_fn(
-    ptr: *const u8,
-    size: usize,
-    cb: unsafe extern "C" fn(*mut c_void, *const u8, usize),
-    user_data: *mut c_void,
-) {
-    unsafe { cb(user_data, ptr, size) }
+pub unsafe extern "C" fn _wasm_resolve(request_id: u64, ptr: *const u8, size: usize) -> bool {
+    if ptr.is_null() {
+        let reply = encode_reply(Err(Error::OutOfMemory));
+        return PENDING_CALLS.with(|calls| calls.resolve(request_id, &reply));
+    }
+    let data = core::slice::from_raw_parts(ptr, size);
+    PENDING_CALLS.with(|calls| calls.resolve(request_id, data))
 }
 
 #[no_mangle]
 pub extern "C" fn set_instance_id(id: u64) -> bool {
+    #[cfg(target_arch = "wasm32")]
+    unsafe {
+        core::ptr::read_volatile(&crate::abi::WE_ABI);
+    }
     INSTANCE_ID.set(id).is_ok()
 }
 
@@ -0,0 +1,52 @@
+//! Key-value store provided by the host.
+//!
+//! Every module has a namespace of its own, keys of other modules cannot be read or written.
+
+use alloc::vec::Vec;
+
+use serde::de::DeserializeOwned;
+use semi_async::AsyncResult;
+
+use crate::{error, internal, Result};
+
+/// `(key, value)` pairs, in key order.
+pub type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+pub fn get<K: AsRef<[u8]>>(key: K) -> AsyncResult<Result<Option<Vec<u8>>>> {
+    let key = key.as_ref();
+    request(|request_id| unsafe { internal::kv_get(key.as_ptr(), key.len(), request_id) })
+}
+
+pub fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(key: K, value: V) -> AsyncResult<Result<()>> {
+    let (key, value) = (key.as_ref(), value.as_ref());
+    request(|request_id| unsafe {
+        internal::kv_put(key.as_ptr(), key.len(), value.as_ptr(), value.len(), request_id)
+    })
+}
+
+/// Resolves to whether `key` existed.
+pub fn delete<K: AsRef<[u8]>>(key: K) -> AsyncResult<Result<bool>> {
+    let key = key.as_ref();
+    request(|request_id| unsafe { internal::kv_delete(key.as_ptr(), key.len(), request_id) })
+}
+
+/// Pairs whose key starts with `prefix`, in key order.
+pub fn scan_prefix<P: AsRef<[u8]>>(prefix: P) -> AsyncResult<Result<Pairs>> {
+    let prefix = prefix.as_ref();
+    request(|request_id| unsafe { internal::kv_scan_prefix(prefix.as_ptr(), prefix.len(), request_id) })
+}
+
+/// The host reads the arguments during `call`, they need not outlive it.
+fn request<R, C>(call: C) -> AsyncResult<Result<R>>
+where
+    R: DeserializeOwned + 'static,
+    C: FnOnce(u64),
+{
+    let result = AsyncResult::default();
+    let inner = result.clone_inner();
+    internal::host_request(call, move |data: &[u8]| {
+        let result = error::decode_reply(data).and_then(|data| bincode::deserialize(data).map_err(|e| e.into()));
+        semi_async::resolve(&inner, result);
+    });
+    result
+}
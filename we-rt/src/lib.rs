@@ -1,53 +1,86 @@
 #![no_std]
 extern crate alloc;
+// only for `thread_local!`, the state of the guest runtime is never shared between threads
+extern crate std;
 
-use core::ops::Deref;
+use alloc::string::String;
 
 pub use semi_async::{AsyncResult, Runtime, MaybeTaken};
 pub use we_logger::init as init_logger;
 
-pub use crate::internal::HostCallback;
+pub use we_macros::{export, service};
+pub use we_codec::{Codec, Format};
+
+pub use crate::service::serve;
 use crate::internal::invoke_callback;
 
 pub type Result<T> = core::result::Result<T, error::Error>;
 
-mod error;
+pub mod abi;
+pub mod error;
+pub mod export;
+pub mod http;
+pub mod kv;
 mod internal;
 mod mem;
+pub mod service;
+pub mod time;
 
 pub fn invoke<N, M, A, R>(name: N, method: M, args: A) -> AsyncResult<Result<R>>
 where
     N: AsRef<str>,
     M: AsRef<str>,
     A: serde::Serialize,
-    R: serde::de::DeserializeOwned,
+    R: serde::de::DeserializeOwned + 'static,
+{
+    invoke_with(Format::Bincode, name, method, args)
+}
+
+/// Like `invoke`, sending `args` and getting the reply in `format`.
+pub fn invoke_with<N, M, A, R>(format: Format, name: N, method: M, args: A) -> AsyncResult<Result<R>>
+where
+    N: AsRef<str>,
+    M: AsRef<str>,
+    A: serde::Serialize,
+    R: serde::de::DeserializeOwned + 'static,
 {
     let result = AsyncResult::default();
     let inner = result.clone_inner();
 
-    match bincode::serialize(&args) {
-        Ok(args_value) => invoke_callback(name.as_ref().as_bytes(), method.as_ref().as_bytes(), args_value,move |data: &[u8]| {
-            let mut inner = inner.deref().borrow_mut();
-            inner.set_value(bincode::deserialize(data).map_err(|e| e.into()));
-
-            let task_op = inner.waker_ref();
-            if task_op.is_some() {
-                task_op.unwrap().wake_by_ref();
-            };
+    match format.encode(&args) {
+        Ok(args_value) => invoke_callback(name.as_ref(), method.as_ref(), format, &args_value, move |data: &[u8]| {
+            let result = error::decode_reply(data).and_then(|data| format.decode(data).map_err(|e| e.into()));
+            semi_async::resolve(&inner, result);
         }),
-        Err(e) => inner.deref().borrow_mut().set_value(Err(e.into()))
+        Err(e) => return AsyncResult::ready(Err(e.into())),
     };
 
     result
 }
 
-pub fn callback(data: &[u8], cb: i64, user_data: i64) {
+/// Reply the outcome of the host call `request_id`, errors reach the caller as `Error::Remote`.
+pub fn callback(result: Result<&[u8]>, request_id: u64) {
+    let data = error::encode_reply(result);
     unsafe {
         internal::callback(
+            request_id,
             data.as_ptr(),
-            data.len(),
-            cb,
-            user_data
+            data.len()
         )
     }
 }
+
+/// Read a config value the host deployment provides for this module.
+pub fn config<K: AsRef<str>>(key: K) -> Option<String> {
+    let key = key.as_ref();
+    let mut ret = [0usize; 2];
+    unsafe {
+        if !internal::config(key.as_ptr(), key.len(), ret.as_mut_ptr()) {
+            return None;
+        }
+        let value = core::slice::from_raw_parts(ret[0] as *const u8, ret[1]);
+        let value = String::from_utf8_lossy(value).into_owned();
+        mem::_wasm_free(ret[0] as *mut u8, ret[1]);
+        Some(value)
+    }
+}
@@ -1,21 +1,19 @@
-use core::{alloc::Layout, mem};
+use core::{alloc::Layout, mem, ptr};
 
+/// Allocate memory for the host, null if the allocation fails.
 #[no_mangle]
 pub extern "C" fn _wasm_malloc(size: usize) -> *mut u8 {
     let align = mem::align_of::<usize>();
     if let Ok(layout) = Layout::from_size_align(size, align) {
         unsafe {
             if layout.size() > 0 {
-                let ptr = alloc::alloc::alloc(layout);
-                if !ptr.is_null() {
-                    return ptr;
-                }
+                return alloc::alloc::alloc(layout);
             } else {
                 return align as *mut u8;
             }
         }
     }
-    panic!("malloc error");
+    ptr::null_mut()
 }
 
 #[no_mangle]
@@ -0,0 +1,78 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::future::Future;
+use core::pin::Pin;
+
+use we_proto::{Format, Request};
+
+use crate::{callback, internal::spawn_request, Result};
+
+pub type LocalBoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+pub type DispatchFuture = LocalBoxFuture<'static, Result<Vec<u8>>>;
+
+/// A service a guest serves to other modules, usually generated by `#[we_rt::service]`.
+pub trait Service {
+    /// Name callers address the service by.
+    fn name(&self) -> &str;
+
+    /// Decode `args` and start serving `method`, `None` if there is no such method.
+    ///
+    /// The reply is encoded in `format`, the format of `args`.
+    fn dispatch(&'static self, method: &str, format: Format, args: &[u8]) -> Option<DispatchFuture>;
+}
+
+std::thread_local! {
+    static SERVICES: RefCell<Vec<&'static dyn Service>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Register a service, the host routes `invoke` calls for its name to this guest.
+pub fn serve<S: Service + 'static>(service: S) {
+    let service: &'static dyn Service = Box::leak(Box::new(service));
+    SERVICES.with(|services| services.borrow_mut().push(service));
+}
+
+#[inline]
+pub fn boxed<'a, F: Future + 'a>(future: F) -> LocalBoxFuture<'a, F::Output> {
+    Box::pin(future)
+}
+
+#[inline]
+pub fn decode<T: serde::de::DeserializeOwned>(format: Format, data: &[u8]) -> Result<T> {
+    format.decode(data).map_err(|e| e.into())
+}
+
+#[inline]
+pub fn encode<T: serde::Serialize>(format: Format, value: &T) -> Result<Vec<u8>> {
+    format.encode(value).map_err(|e| e.into())
+}
+
+/// Serve a `Request` envelope with the registered dispatcher of its service.
+///
+/// # Safety
+///
+/// Only called by the host, with `request_ptr` pointing to `request_len` bytes it wrote to guest memory.
+#[no_mangle]
+pub unsafe extern "C" fn _wasm_dispatch(request_ptr: *const u8, request_len: usize, request_id: u64) -> bool {
+    let request = match Request::decode(core::slice::from_raw_parts(request_ptr, request_len)) {
+        Ok(request) => request,
+        Err(e) => {
+            callback(Err(e.into()), request_id);
+            return true;
+        }
+    };
+
+    let service = SERVICES.with(|services| services.borrow().iter().find(|s| s.name() == request.service).copied());
+    let future = match service.and_then(|service| service.dispatch(request.method, request.format, request.args)) {
+        Some(future) => future,
+        None => return false,
+    };
+
+    spawn_request(request_id, async move {
+        match future.await {
+            Ok(data) => callback(Ok(&data), request_id),
+            Err(e) => callback(Err(e), request_id),
+        }
+    });
+    true
+}
@@ -0,0 +1,92 @@
+//! Clock and timers provided by the host, timers resume the awaiting task when they fire.
+
+use core::ops::{Add, Sub};
+pub use core::time::Duration;
+
+use semi_async::AsyncResult;
+
+use crate::internal;
+
+/// A reading of the host's monotonic clock, shared by all instances.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(unsafe { internal::now() })
+    }
+
+    /// Time elapsed from `earlier` to `self`, zero if `earlier` is later.
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().saturating_duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0.saturating_add(duration.as_nanos() as u64))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.saturating_duration_since(earlier)
+    }
+}
+
+/// Wait until `duration` elapsed.
+pub fn sleep(duration: Duration) -> AsyncResult<()> {
+    if duration == Duration::from_nanos(0) {
+        return AsyncResult::ready(());
+    }
+    let result = AsyncResult::default();
+    let inner = result.clone_inner();
+    // any reply means the timer fired, there is nothing to decode
+    let nanos = duration.as_nanos() as u64;
+    internal::host_request(
+        |request_id| unsafe { internal::sleep(nanos, request_id) },
+        move |_| semi_async::resolve(&inner, ()),
+    );
+    result
+}
+
+/// Wait until `deadline`, completes right away if it passed.
+pub fn sleep_until(deadline: Instant) -> AsyncResult<()> {
+    sleep(deadline.saturating_duration_since(Instant::now()))
+}
+
+/// Ticks every `period`, the first tick completes right away.
+pub fn interval(period: Duration) -> Interval {
+    Interval { next: Instant::now(), period }
+}
+
+/// Periodic timer created by `interval`.
+///
+/// Missed ticks are not skipped, a late tick is followed by ticks in quick succession until it caught up.
+#[derive(Debug)]
+pub struct Interval {
+    next: Instant,
+    period: Duration,
+}
+
+impl Interval {
+    /// Wait for the next tick, returns when it was scheduled.
+    pub async fn tick(&mut self) -> Instant {
+        let deadline = self.next;
+        self.next = deadline + self.period;
+        sleep_until(deadline).await;
+        deadline
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+}